@@ -0,0 +1,255 @@
+//Rotating backups of the vault.
+//
+//Before a command changes the vault, the master key file and credential DB
+//are copied, as they are on disk, into a new generation under 
+//"<DB file>.backups/<id>", where the id is the UTC time of the backup. Only
+//the newest generations are kept. Backups are encrypted like the vault 
+//itself. A vault migrated from an older format is not backed up, and backups
+//of an older format are removed: their master key file may hold a key the 
+//migrated vault no longer uses (the bare SHA-256 master key of the oldest 
+//vaults, or a master key never moved to a key slot, so never revoked).
+//
+//Every backup wraps the same vault key, so a key slot removed from the vault
+//(a revoked password, a used or replaced recovery code) is removed from the
+//master key file of every backup as well: it must not unlock an older copy.
+//Restoring a backup brings back its credentials and vault settings, but 
+//keeps the key slots of the vault, so a slot revoked since does not come 
+//back, nor is a slot added since lost.
+
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::{decrypt_credential, decrypt_previous_password, 
+            parse_master_key_file, verify_vault_key, MasterKeyRecord};
+use crate::integrity::{self, IntegrityCheck};
+use crate::persist;
+use crate::secret::SecretKey;
+use crate::storage::{self, StorageKind};
+use crate::structs::{KeySlot, MasterKeyFile};
+use crate::throttle::{now_secs, utc_date_time};
+
+//Generations kept if the vault does not configure it
+pub const DEFAULT_BACKUP_GENERATIONS: u32 = 5;
+
+//A backup generation
+pub struct Backup {
+    pub id: String,
+    dir: PathBuf,
+    key_file_name: String,
+    db_file_name: String,
+}
+
+impl Backup {
+    /// Path of the backed up master key file
+    pub fn key_file(&self) -> PathBuf {
+        self.dir.join(&self.key_file_name)
+    }
+
+    /// Path of the backed up credential DB. A vault whose DB did not exist
+    /// yet has none.
+    pub fn db_file(&self) -> Option<PathBuf> {
+        let db_file = self.dir.join(&self.db_file_name);
+        db_file.exists().then_some(db_file)
+    }
+}
+
+//Directory holding the backups of the vault whose DB is `db_file_name`
+fn backups_dir(db_file_name: &str) -> PathBuf {
+    PathBuf::from(format!("{}.backups", db_file_name))
+}
+
+//Name of a file within its directory
+fn base_name(file_name: &str) -> Result<String, Box<dyn Error>> {
+    Path::new(file_name).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("Invalid file name {}", file_name).into())
+}
+
+//Formats seconds since the UNIX epoch as a UTC "YYYYMMDD-HHMMSS" id
+fn timestamp_id(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = utc_date_time(secs);
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", 
+            year, month, day, hour, minute, second)
+}
+
+/// Backs up the master key file and credential DB as they are on disk.
+/// The generation only appears once completely written.
+pub fn create(db_file_name: &str, master_key_file_name: &str) 
+       -> Result<Backup, Box<dyn Error>> {
+    let dir = backups_dir(db_file_name);
+    fs::create_dir_all(&dir)?;
+    let base_id = timestamp_id(now_secs());
+    let mut id = base_id.clone();
+    let mut suffix = 1;
+    while dir.join(&id).exists() {
+        suffix += 1;
+        id = format!("{}-{}", base_id, suffix);
+    }
+
+    let backup = Backup {
+        dir: dir.join(&id),
+        id,
+        key_file_name: base_name(master_key_file_name)?,
+        db_file_name: base_name(db_file_name)?,
+    };
+    let temp_dir = dir.join(format!("{}.tmp", backup.id));
+    fs::create_dir_all(&temp_dir)?;
+    fs::copy(master_key_file_name, temp_dir.join(&backup.key_file_name))?;
+    match fs::copy(db_file_name, temp_dir.join(&backup.db_file_name)) {
+        Err(error) if error.kind() != ErrorKind::NotFound => 
+            return Err(error.into()),
+        _ => {},
+    }
+    for entry in fs::read_dir(&temp_dir)? {
+        fs::File::open(entry?.path())?.sync_all()?;
+    }
+    fs::rename(&temp_dir, &backup.dir)?;
+    persist::sync_dir(&dir)?;
+    Ok(backup)
+}
+
+/// Lists the backups of the vault, oldest first
+pub fn list(db_file_name: &str, master_key_file_name: &str) 
+       -> Result<Vec<Backup>, Box<dyn Error>> {
+    let dir = backups_dir(db_file_name);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => 
+            return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let key_file_name = base_name(master_key_file_name)?;
+    let db_file_name = base_name(db_file_name)?;
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let id = entry.file_name().to_string_lossy().into_owned();
+        //Skip generations still being written
+        if !entry.path().is_dir() || id.ends_with(".tmp") {
+            continue;
+        }
+        backups.push(Backup {
+            id,
+            dir: entry.path(),
+            key_file_name: key_file_name.clone(),
+            db_file_name: db_file_name.clone(),
+        });
+    }
+    backups.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(backups)
+}
+
+/// Finds backup `id` of the vault
+pub fn find(db_file_name: &str, master_key_file_name: &str, id: &str) 
+       -> Result<Backup, Box<dyn Error>> {
+    list(db_file_name, master_key_file_name)?.into_iter()
+        .find(|backup| backup.id == id)
+        .ok_or_else(|| format!("No backup {}", id).into())
+}
+
+//Whether the master key file of `backup` is in a format older than key
+//slots and bound entries
+fn is_outdated(backup: &Backup) -> Result<bool, Box<dyn Error>> {
+    let key_file_content = fs::read_to_string(backup.key_file())?;
+    Ok(match parse_master_key_file(&key_file_content)? {
+        MasterKeyRecord::Current(key_file) => !key_file.entries_bound || 
+            key_file.verifier.is_some() || key_file.wrapped_key.is_some() ||
+            key_file.master_key_hash.is_some(),
+        MasterKeyRecord::Legacy(_) => true,
+    })
+}
+
+/// Removes the backups in an older format and all but the `keep` newest 
+/// others, returns the removed ones
+pub fn prune(db_file_name: &str, master_key_file_name: &str, keep: usize) 
+       -> Result<Vec<Backup>, Box<dyn Error>> {
+    let mut removed = Vec::new();
+    let mut backups = Vec::new();
+    for backup in list(db_file_name, master_key_file_name)? {
+        if is_outdated(&backup)? {
+            removed.push(backup);
+        }
+        else {
+            backups.push(backup);
+        }
+    }
+    let excess = backups.len().saturating_sub(keep);
+    removed.extend(backups.drain(..excess));
+    for backup in removed.iter() {
+        fs::remove_dir_all(&backup.dir)?;
+    }
+    Ok(removed)
+}
+
+/// Removes the key `slots` from the master key file of every backup. Slots
+/// are told apart by their verifier, as ids may be reused. Backups in an
+/// older format have no slots, they are removed by prune(). Returns the 
+/// number of backups changed.
+pub fn remove_key_slots(db_file_name: &str, master_key_file_name: &str, 
+                        slots: &[KeySlot]) -> Result<usize, Box<dyn Error>> {
+    let mut changed = 0;
+    for backup in list(db_file_name, master_key_file_name)? {
+        let key_file_content = fs::read_to_string(backup.key_file())?;
+        let MasterKeyRecord::Current(mut key_file) = 
+            parse_master_key_file(&key_file_content)? else {
+            continue;
+        };
+        let slot_count = key_file.slots.len();
+        key_file.slots.retain(|slot| 
+            !slots.iter().any(|removed| removed.verifier == slot.verifier));
+        if key_file.slots.len() < slot_count {
+            persist::write_atomic(&backup.key_file().to_string_lossy(), 
+                serde_json::to_string_pretty(&key_file)?.as_bytes())?;
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+/// Checks that the backed up vault opens with `vault_key`: the vault key 
+/// check value matches, and every credential (or the sealed DB) decrypts.
+/// Returns the backed up master key file and DB contents.
+pub fn verify(backup: &Backup, vault_key: &SecretKey, kind: StorageKind) 
+       -> Result<(MasterKeyFile, Vec<u8>), Box<dyn Error>> {
+    let key_file_content = fs::read_to_string(backup.key_file())?;
+    let key_file = match parse_master_key_file(&key_file_content)? {
+        MasterKeyRecord::Current(key_file) if key_file.entries_bound => *key_file,
+        _ => return Err(format!("Backup {} was written by an older version \
+                                 and cannot be verified", backup.id).into()),
+    };
+    if key_file.vault_key_check.is_some() {
+        verify_vault_key(&key_file, vault_key)?;
+    }
+    let db_file = backup.db_file()
+        .ok_or_else(|| format!("Backup {} has no credential DB", backup.id))?;
+    let db_file_content = fs::read(&db_file)?;
+    let mut db = storage::open(&db_file.to_string_lossy(), kind, 
+                               Some(vault_key), key_file.sealed)?;
+    if let IntegrityCheck::Incomplete(reason) = integrity::check(
+        &db.load()?, db.integrity().as_ref(), &key_file, vault_key)? {
+        return Err(format!("Backup {}: {}", backup.id, reason).into());
+    }
+    for (site, user, cred) in db.entries(None)? {
+        decrypt_credential(&site, &user, &cred, vault_key)?;
+        for previous in cred.history.iter() {
+            decrypt_previous_password(&site, &user, previous, vault_key)?;
+        }
+    }
+    Ok((key_file, db_file_content))
+}
+
+/// Master key file restoring the vault settings of `backed_up`, unlocked by
+/// the key slots of `current` (the key slots are not restored)
+pub fn restored_key_file(mut backed_up: MasterKeyFile, 
+                         current: &mut MasterKeyFile) -> MasterKeyFile {
+    backed_up.slots = std::mem::take(&mut current.slots);
+    backed_up.kdf = current.kdf.take();
+    backed_up.verifier = current.verifier.take();
+    backed_up.wrapped_key = current.wrapped_key.take();
+    backed_up.master_key_hash = current.master_key_hash.take();
+    backed_up.throttle = current.throttle.take();
+    backed_up.recovery_kit |= current.recovery_kit;
+    backed_up
+}
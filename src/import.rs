@@ -0,0 +1,291 @@
+//Import of credentials from a 'raw' credentials file.
+//
+//A raw credentials file holds one credential per line:
+//    <site> <user> <username> <password>
+//Fields are separated by spaces or tabs. A field containing spaces is put
+//in double quotes, inside which \" stands for a quote and \\ for a
+//backslash. Blank lines and lines starting with '#' are skipped. The file
+//is read a line at a time; a malformed line is reported as
+//"<file>:<line>: <reason>" and skipped, without stopping the import.
+//
+//Credentials are either imported into an empty vault (replacing it), or
+//merged into the credentials stored already. A merged credential whose site
+//and user are taken, by a different username or password, is a conflict,
+//resolved by the ConflictPolicy.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+use crate::secret::SecretString;
+
+//A credential read from a raw credentials file, password in cleartext
+pub struct RawCredential {
+    pub site: String,
+    pub user: String,
+    pub username: String,
+    pub password: SecretString,
+}
+
+//How to merge an imported credential that conflicts with a stored one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    //Keep the stored credential
+    Skip,
+    //Replace the stored credential (its password goes into the history)
+    Overwrite,
+    //Keep the stored credential, and import the other one under a new 
+    //user: "<user> (2)", "<user> (3)", ...
+    KeepBoth,
+    //Ask for each conflict
+    Prompt,
+}
+
+impl ConflictPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::KeepBoth => "keep-both",
+            ConflictPolicy::Prompt => "prompt",
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "prompt" => Ok(ConflictPolicy::Prompt),
+            _ => Err(format!("Unknown conflict policy {} (expected skip, \
+                              overwrite, keep-both or prompt)", name)),
+        }
+    }
+}
+
+/// User the `n`th credential of `user` is kept under, when keeping both
+/// sides of conflicts
+pub fn numbered_user(user: &str, n: u32) -> String {
+    format!("{} ({})", user, n)
+}
+
+//Outcome of an import
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    //Lines skipped, each as "<file>:<line>: <reason>"
+    pub skipped: Vec<String>,
+}
+
+//Splits a line into its fields, unquoting quoted ones
+fn split_fields(line: &str) -> Result<Vec<Zeroizing<String>>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(fields);
+        };
+        let mut field = Zeroizing::new(String::new());
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\')) => field.push(c),
+                        _ => return Err(format!(
+                            "field {}: only \\\" and \\\\ can be escaped in \
+                             quotes", fields.len() + 1)),
+                    },
+                    Some(c) => field.push(c),
+                    None => return Err(format!(
+                        "field {}: missing closing quote", fields.len() + 1)),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err(format!("field {}: closing quote must be followed \
+                                    by a space", fields.len() + 1));
+            }
+        }
+        else {
+            field.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                field.push(c);
+            }
+        }
+        fields.push(field);
+    }
+}
+
+//Parses a line of a raw credentials file, None for blank and comment lines
+fn parse_line(line: &str) -> Result<Option<RawCredential>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields = split_fields(line)?;
+    let [site, user, username, password] = fields.as_slice() else {
+        return Err(format!("expected 4 fields (<site> <user> <username> \
+                            <password>), found {}", fields.len()));
+    };
+    for (name, value) in [("site", site), ("user", user),
+                          ("password", password)] {
+        if value.is_empty() {
+            return Err(format!("empty {}", name));
+        }
+    }
+    Ok(Some(RawCredential {
+        site: site.as_str().to_owned(),
+        user: user.as_str().to_owned(),
+        username: username.as_str().to_owned(),
+        password: SecretString::from(password.as_str().to_owned()),
+    }))
+}
+
+//Collects the credentials read from a file: hands each on to be imported,
+//unless one of the same site and user was read already, and sums up the
+//import. Credentials and skipped entries are located as "<file>:<line>" 
+//(or whatever locates them in the file).
+pub struct ImportSink<F> {
+    summary: ImportSummary,
+    //Location of each site and user imported
+    seen: HashMap<(String, String), String>,
+    import: F,
+}
+
+impl<F: FnMut(RawCredential) -> Result<(), Box<dyn Error>>> ImportSink<F> {
+    pub fn new(import: F) -> ImportSink<F> {
+        ImportSink {
+            summary: ImportSummary::default(),
+            seen: HashMap::new(),
+            import,
+        }
+    }
+
+    /// Imports the credential read at `location`
+    pub fn add(&mut self, location: String, cred: RawCredential)
+           -> Result<(), Box<dyn Error>> {
+        let key = (cred.site.clone(), cred.user.clone());
+        if let Some(first) = self.seen.get(&key) {
+            let reason = format!("user {} on site {} already imported from {}",
+                                 cred.user, cred.site, first);
+            self.skip(&location, &reason);
+            return Ok(());
+        }
+        (self.import)(cred)?;
+        self.seen.insert(key, location);
+        self.summary.imported += 1;
+        Ok(())
+    }
+
+    /// Skips the entry at `location`
+    pub fn skip(&mut self, location: &str, reason: &str) {
+        self.summary.skipped.push(format!("{}: {}", location, reason));
+    }
+
+    pub fn finish(self) -> ImportSummary {
+        self.summary
+    }
+}
+
+/// Reads the raw credentials file `file_name` a line at a time, and hands
+/// each credential to `sink`
+pub fn read_raw_credentials<F>(file_name: &str, sink: &mut ImportSink<F>)
+       -> Result<(), Box<dyn Error>>
+       where F: FnMut(RawCredential) -> Result<(), Box<dyn Error>> {
+    let file = File::open(file_name)
+        .map_err(|error| format!("Could not open {}: {}", file_name, error))?;
+    let mut reader = BufReader::new(file);
+    let mut buffer = Zeroizing::new(Vec::new());
+    let mut line_number = 0;
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)
+            .map_err(|error| format!("Could not read {}: {}", file_name,
+                                     error))? == 0 {
+            return Ok(());
+        }
+        line_number += 1;
+        let location = format!("{}:{}", file_name, line_number);
+        match std::str::from_utf8(&buffer)
+            .map_err(|_| "not valid UTF-8".to_string())
+            .and_then(parse_line) {
+            Ok(Some(cred)) => sink.add(location, cred)?,
+            Ok(None) => {},
+            Err(reason) => sink.skip(&location, &reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(line: &str) -> Vec<String> {
+        split_fields(line).unwrap().iter()
+            .map(|field| field.as_str().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn split_fields_unquotes_and_unescapes() {
+        assert_eq!(fields("a\t b  c"), ["a", "b", "c"]);
+        assert_eq!(fields(r#"site "my user" "" "p\"w\\d""#),
+                   ["site", "my user", "", r#"p"w\d"#]);
+        assert_eq!(fields("  "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_fields_rejects_bad_quoting() {
+        assert!(split_fields(r#"a "b\n""#).unwrap_err()
+                .contains("can be escaped"));
+        assert!(split_fields(r#"a "b c"#).unwrap_err()
+                .contains("missing closing quote"));
+        assert!(split_fields(r#"a "b"c"#).unwrap_err()
+                .contains("followed by a space"));
+    }
+
+    #[test]
+    fn parse_line_skips_blank_and_comment_lines() {
+        assert!(parse_line("").unwrap().is_none());
+        assert!(parse_line("  \t\r\n").unwrap().is_none());
+        assert!(parse_line("  # site user username password").unwrap()
+                .is_none());
+    }
+
+    #[test]
+    fn parse_line_reads_credential() {
+        let cred = parse_line("gmail.com me \"\" \"pass word\"\r\n")
+            .unwrap().unwrap();
+        assert_eq!(cred.site, "gmail.com");
+        assert_eq!(cred.user, "me");
+        assert_eq!(cred.username, "");
+        assert_eq!(cred.password.expose(), "pass word");
+    }
+
+    //Why `line` is skipped (RawCredential is not Debug, so no unwrap_err)
+    fn error(line: &str) -> String {
+        parse_line(line).err().expect("line should be skipped")
+    }
+
+    #[test]
+    fn parse_line_rejects_missing_fields() {
+        assert!(error("gmail.com me name").contains("found 3"));
+        assert_eq!(error("gmail.com me name \"\""), "empty password");
+        assert_eq!(error("\"\" me name pw"), "empty site");
+    }
+}
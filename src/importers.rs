@@ -0,0 +1,477 @@
+//Importers of the exports of other password managers.
+//
+//Each reads the logins of an export (a file, or for pass a password store
+//directory) and hands them to an ImportSink (see import.rs). The site of a
+//login is the host name of its URL, e.g. "accounts.google.com" for
+//"https://accounts.google.com/signin" (or its name when it has no URL), and
+//the user is its account name, i.e. its username ("self" without one).
+//Entries that are not logins, such as notes and cards, are skipped. Unless
+//given, the format is detected from the contents.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use crate::import::{self, ImportSink, RawCredential};
+use crate::secret::SecretString;
+
+//User of a login without a username
+const DEFAULT_USER: &str = "self";
+
+//Format of a file (or directory) to import
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    //Detect the format
+    Auto,
+    //pwmgr raw credentials file
+    Raw,
+    //CSV export of Chrome (and other Chromium based browsers)
+    Chrome,
+    //CSV export of Firefox
+    Firefox,
+    //Unencrypted JSON export of Bitwarden
+    Bitwarden,
+    //XML export of KeePass 2
+    Keepass,
+    //CSV export of LastPass
+    Lastpass,
+    //pass (password-store) directory, decrypted with gpg
+    Pass,
+}
+
+impl ImportFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImportFormat::Auto => "auto",
+            ImportFormat::Raw => "raw",
+            ImportFormat::Chrome => "chrome",
+            ImportFormat::Firefox => "firefox",
+            ImportFormat::Bitwarden => "bitwarden",
+            ImportFormat::Keepass => "keepass",
+            ImportFormat::Lastpass => "lastpass",
+            ImportFormat::Pass => "pass",
+        }
+    }
+}
+
+impl fmt::Display for ImportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "auto" => Ok(ImportFormat::Auto),
+            "raw" => Ok(ImportFormat::Raw),
+            "chrome" => Ok(ImportFormat::Chrome),
+            "firefox" => Ok(ImportFormat::Firefox),
+            "bitwarden" => Ok(ImportFormat::Bitwarden),
+            "keepass" => Ok(ImportFormat::Keepass),
+            "lastpass" => Ok(ImportFormat::Lastpass),
+            "pass" => Ok(ImportFormat::Pass),
+            _ => Err(format!("Unknown import format {} (expected auto, raw, \
+                              chrome, firefox, bitwarden, keepass, lastpass \
+                              or pass)", name)),
+        }
+    }
+}
+
+/// Detects the format of `path`: a directory is a password store, JSON a
+/// Bitwarden export, XML a KeePass export, CSV is told apart by its header
+/// line, and anything else is a raw credentials file
+pub fn detect_format(path: &str) -> Result<ImportFormat, Box<dyn Error>> {
+    if Path::new(path).is_dir() {
+        return Ok(ImportFormat::Pass);
+    }
+    let mut head = Zeroizing::new(Vec::new());
+    File::open(path)
+        .and_then(|file| file.take(4096).read_to_end(&mut head))
+        .map_err(|error| format!("Could not open {}: {}", path, error))?;
+    let head = String::from_utf8_lossy(&head);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    if head.starts_with('{') {
+        return Ok(ImportFormat::Bitwarden);
+    }
+    if head.starts_with('<') {
+        return Ok(ImportFormat::Keepass);
+    }
+    let columns: Vec<String> = head.lines().next().unwrap_or_default()
+        .split(',')
+        .map(|column| column.trim().trim_matches('"').to_lowercase())
+        .collect();
+    let has = |name: &str| columns.iter().any(|column| column == name);
+    Ok(if !(has("url") && has("username") && has("password")) {
+        ImportFormat::Raw
+    }
+    else if has("httprealm") || has("formactionorigin") {
+        ImportFormat::Firefox
+    }
+    else if has("grouping") || has("extra") {
+        ImportFormat::Lastpass
+    }
+    else {
+        ImportFormat::Chrome
+    })
+}
+
+/// Reads the credentials of `path` in `format` (detected if Auto), and
+/// hands each to `sink`
+pub fn read_credentials<F>(path: &str, format: ImportFormat,
+                           sink: &mut ImportSink<F>)
+       -> Result<(), Box<dyn Error>>
+       where F: FnMut(RawCredential) -> Result<(), Box<dyn Error>> {
+    let format = match format {
+        ImportFormat::Auto => detect_format(path)?,
+        format => format,
+    };
+    match format {
+        ImportFormat::Auto | ImportFormat::Raw =>
+            import::read_raw_credentials(path, sink),
+        ImportFormat::Chrome | ImportFormat::Firefox | ImportFormat::Lastpass =>
+            read_csv(path, format, sink),
+        ImportFormat::Bitwarden => read_bitwarden(path, sink),
+        ImportFormat::Keepass => read_keepass(path, sink),
+        ImportFormat::Pass => read_password_store(path, sink),
+    }
+}
+
+/// Site of the login URL `url`: its host name, without "www." and port
+pub fn site_from_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = match host.strip_prefix('[') {
+        //IPv6 address
+        Some(address) => address.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    let host = host.strip_prefix("www.").unwrap_or(host).to_lowercase();
+    (!host.is_empty()).then_some(host)
+}
+
+//The credential of a login, or why it is skipped
+fn login(name: &str, url: &str, username: &str, password: &str)
+   -> Result<RawCredential, String> {
+    if password.is_empty() {
+        return Err("no password".to_string());
+    }
+    let site = site_from_url(url)
+        .or_else(|| Some(name.trim()).filter(|name| !name.is_empty())
+                 .map(str::to_string))
+        .ok_or("no URL or name")?;
+    let username = username.trim();
+    Ok(RawCredential {
+        site,
+        user: if username.is_empty() {DEFAULT_USER} else {username}.to_string(),
+        username: username.to_string(),
+        password: SecretString::from(password.to_string()),
+    })
+}
+
+//Reads a CSV export of Chrome, Firefox or LastPass. All have a header line
+//naming the url, username and password columns, and Chrome and LastPass a
+//name column as well.
+fn read_csv<F>(file_name: &str, format: ImportFormat, sink: &mut ImportSink<F>)
+   -> Result<(), Box<dyn Error>>
+   where F: FnMut(RawCredential) -> Result<(), Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true)
+        .from_path(file_name)
+        .map_err(|error| format!("Could not open {}: {}", file_name, error))?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter()
+        .position(|header| header.trim_start_matches('\u{feff}')
+                  .eq_ignore_ascii_case(name));
+    let [url, username, password] = ["url", "username", "password"]
+        .map(column);
+    let (Some(url), Some(username), Some(password)) = (url, username, password)
+    else {
+        return Err(format!("{} is not a {} CSV export (it needs url, username \
+                            and password columns)", file_name, format).into());
+    };
+    let name = column("name");
+    let mut record = csv::StringRecord::new();
+    loop {
+        let location = format!("{}:{}", file_name,
+                               reader.position().line());
+        match reader.read_record(&mut record) {
+            Ok(false) => return Ok(()),
+            Ok(true) => {},
+            Err(error) => {
+                //A malformed record cannot be read past
+                sink.skip(&location, &error.to_string());
+                return Ok(());
+            },
+        }
+        let field = |index: usize| record.get(index).unwrap_or_default();
+        //LastPass exports secure notes with this URL
+        if format == ImportFormat::Lastpass && field(url) == "http://sn" {
+            sink.skip(&location, "secure note, not a login");
+            continue;
+        }
+        match login(name.map(field).unwrap_or_default(), field(url),
+                    field(username), field(password)) {
+            Ok(cred) => sink.add(location, cred)?,
+            Err(reason) => sink.skip(&location, &reason),
+        }
+    }
+}
+
+//Unencrypted JSON export of Bitwarden (only what is imported)
+#[derive(Deserialize)]
+struct BitwardenExport {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default, rename = "passwordProtected")]
+    password_protected: bool,
+    #[serde(default)]
+    items: Vec<BitwardenItem>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenItem {
+    #[serde(default)]
+    name: Option<String>,
+    //1 for logins
+    #[serde(rename = "type")]
+    kind: u32,
+    #[serde(default)]
+    login: Option<BitwardenLogin>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenLogin {
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    uris: Option<Vec<BitwardenUri>>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenUri {
+    #[serde(default)]
+    uri: Option<String>,
+}
+
+//Reads a Bitwarden JSON export. Only the first URI of a login is imported.
+fn read_bitwarden<F>(file_name: &str, sink: &mut ImportSink<F>)
+   -> Result<(), Box<dyn Error>>
+   where F: FnMut(RawCredential) -> Result<(), Box<dyn Error>> {
+    let contents = Zeroizing::new(fs::read_to_string(file_name)
+        .map_err(|error| format!("Could not open {}: {}", file_name, error))?);
+    let export: BitwardenExport = serde_json::from_str(&contents)
+        .map_err(|error| format!("{} is not a Bitwarden JSON export ({})",
+                                 file_name, error))?;
+    if export.encrypted || export.password_protected {
+        return Err(format!("{} is an encrypted Bitwarden export, export the \
+                            vault as unencrypted JSON", file_name).into());
+    }
+    for (index, item) in export.items.into_iter().enumerate() {
+        let name = item.name.unwrap_or_default();
+        let location = format!("{}: item {} ({:?})", file_name, index + 1,
+                               name);
+        let item_login = match item.login {
+            Some(item_login) if item.kind == 1 => item_login,
+            _ => {
+                sink.skip(&location, "not a login");
+                continue;
+            },
+        };
+        let password = Zeroizing::new(item_login.password.unwrap_or_default());
+        let url = item_login.uris.unwrap_or_default().into_iter()
+            .find_map(|uri| uri.uri).unwrap_or_default();
+        match login(&name, &url, &item_login.username.unwrap_or_default(),
+                    &password) {
+            Ok(cred) => sink.add(location, cred)?,
+            Err(reason) => sink.skip(&location, &reason),
+        }
+    }
+    Ok(())
+}
+
+//Value of the String field `key` of a KeePass entry
+fn keepass_field<'a>(entry: roxmltree::Node<'a, '_>, key: &str) -> &'a str {
+    entry.children()
+        .filter(|node| node.has_tag_name("String"))
+        .find(|field| field.children().any(|node| node.has_tag_name("Key") &&
+                                           node.text() == Some(key)))
+        .and_then(|field| field.children()
+                  .find(|node| node.has_tag_name("Value")))
+        .and_then(|value| value.text())
+        .unwrap_or_default()
+}
+
+//Reads a KeePass 2 XML export. The old versions of an entry (its History)
+//and entries in the recycle bin are not imported.
+fn read_keepass<F>(file_name: &str, sink: &mut ImportSink<F>)
+   -> Result<(), Box<dyn Error>>
+   where F: FnMut(RawCredential) -> Result<(), Box<dyn Error>> {
+    let contents = Zeroizing::new(fs::read_to_string(file_name)
+        .map_err(|error| format!("Could not open {}: {}", file_name, error))?);
+    let document = roxmltree::Document::parse(&contents)
+        .map_err(|error| format!("{} is not an XML file ({})", file_name,
+                                 error))?;
+    let root = document.root_element();
+    if !root.has_tag_name("KeePassFile") {
+        return Err(format!("{} is not a KeePass 2 XML export", file_name)
+                   .into());
+    }
+    let recycle_bin = root.descendants()
+        .find(|node| node.has_tag_name("RecycleBinUUID"))
+        .and_then(|node| node.text());
+    let in_recycle_bin = |entry: roxmltree::Node| entry.ancestors()
+        .filter(|node| node.has_tag_name("Group"))
+        .any(|group| group.children()
+             .find(|node| node.has_tag_name("UUID"))
+             .and_then(|uuid| uuid.text())
+             .is_some_and(|uuid| Some(uuid) == recycle_bin));
+    let entries = root.descendants()
+        .filter(|node| node.has_tag_name("Entry") &&
+                node.parent().is_some_and(|parent|
+                    parent.has_tag_name("Group")));
+    for entry in entries {
+        let title = keepass_field(entry, "Title");
+        let location = format!("{}:{} (entry {:?})", file_name,
+                               document.text_pos_at(entry.range().start).row,
+                               title);
+        if in_recycle_bin(entry) {
+            sink.skip(&location, "in the recycle bin");
+            continue;
+        }
+        match login(title, keepass_field(entry, "URL"),
+                    keepass_field(entry, "UserName"),
+                    keepass_field(entry, "Password")) {
+            Ok(cred) => sink.add(location, cred)?,
+            Err(reason) => sink.skip(&location, &reason),
+        }
+    }
+    Ok(())
+}
+
+//The .gpg files under `dir`, but not in hidden directories such as .git
+fn password_store_files(dir: &Path, files: &mut Vec<PathBuf>)
+   -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name().and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.')) {
+            continue;
+        }
+        if path.is_dir() {
+            password_store_files(&path, files)?;
+        }
+        else if path.extension().is_some_and(|ext| ext == "gpg") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+//Reads a pass password store. Every entry is decrypted with gpg (which may
+//ask for the passphrase of the key). Its first line is the password, and
+//"login:", "username:" or "user:" and "url:" lines are the username and URL.
+//Without them, an entry "<site>/<username>" is named after its site and
+//username, and any other entry after its site.
+fn read_password_store<F>(dir: &str, sink: &mut ImportSink<F>)
+   -> Result<(), Box<dyn Error>>
+   where F: FnMut(RawCredential) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    password_store_files(Path::new(dir), &mut files)
+        .map_err(|error| format!("Could not read {}: {}", dir, error))?;
+    files.sort();
+    for file in files {
+        let location = file.to_string_lossy().to_string();
+        let output = Command::new("gpg")
+            .args(["--quiet", "--decrypt"]).arg(&file)
+            .stdin(Stdio::inherit())
+            .output()
+            .map_err(|error| format!("Could not run gpg: {}", error))?;
+        let decrypted = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            sink.skip(&location, &format!("gpg could not decrypt it ({})",
+                                          error.lines().next()
+                                          .unwrap_or_default()));
+            continue;
+        }
+        let Ok(decrypted) = std::str::from_utf8(&decrypted) else {
+            sink.skip(&location, "not valid UTF-8");
+            continue;
+        };
+        let mut lines = decrypted.lines();
+        let password = lines.next().unwrap_or_default();
+        let fields = lines.filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_lowercase(), value.trim()));
+        let (mut username, mut url) = (None, None);
+        for (key, value) in fields {
+            match key.as_str() {
+                "login" | "username" | "user" =>
+                    username = username.or(Some(value)),
+                "url" => url = url.or(Some(value)),
+                _ => {},
+            }
+        }
+        let relative = file.strip_prefix(dir).unwrap_or(&file)
+            .with_extension("");
+        let names: Vec<String> = relative.iter()
+            .map(|name| name.to_string_lossy().to_string()).collect();
+        let (site, file_username) = match names.as_slice() {
+            [.., site, username] => (site.as_str(), Some(username.as_str())),
+            [site] => (site.as_str(), None),
+            [] => continue,
+        };
+        match login(site, url.unwrap_or_default(),
+                    username.or(file_username).unwrap_or_default(), password) {
+            Ok(cred) => sink.add(location, cred)?,
+            Err(reason) => sink.skip(&location, &reason),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn site_is_host_without_www_and_port() {
+        assert_eq!(site_from_url("https://www.Example.com:8443/login?next=/"),
+                   Some("example.com".to_string()));
+        assert_eq!(site_from_url(" accounts.google.com/signin "),
+                   Some("accounts.google.com".to_string()));
+        assert_eq!(site_from_url("http://example.org#top"),
+                   Some("example.org".to_string()));
+    }
+
+    #[test]
+    fn site_drops_userinfo() {
+        assert_eq!(site_from_url("ftp://me:p@ss@files.example.net:21/"),
+                   Some("files.example.net".to_string()));
+    }
+
+    #[test]
+    fn site_of_ipv6_address() {
+        assert_eq!(site_from_url("http://[fe80::1]:8080/admin"),
+                   Some("fe80::1".to_string()));
+        assert_eq!(site_from_url("https://[::1]/"), Some("::1".to_string()));
+    }
+
+    #[test]
+    fn no_site_without_host() {
+        assert_eq!(site_from_url(""), None);
+        assert_eq!(site_from_url("https:///path"), None);
+        assert_eq!(site_from_url("https://user@:443/"), None);
+    }
+}
@@ -0,0 +1,238 @@
+//Integrity of the credential DB as a whole.
+//
+//Every password is authenticated on its own, which does not stop anyone
+//from deleting whole credentials or sites, or from putting back an older
+//copy of the DB file. So the DB also stores a MAC, keyed with the vault
+//key, over all its credentials and a generation counter incremented on
+//every save, and the master key file remembers the last generation saved,
+//with a MAC of its own. A MAC that does not verify means the DB or the 
+//master key file was tampered with; a valid MAC of an older generation 
+//means the DB was rolled back to an older copy.
+//
+//Once protected, a vault stays so: a DB or master key file lacking the MAC
+//the other one has is reported as incomplete, and is only accepted as it is
+//by an explicit migration. So is a vault that has no MAC at all, as a 
+//stripped vault looks just like one written by an older version.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::secret::SecretKey;
+use crate::structs::{Credential, MasterKeyFile, VaultIntegrity};
+use crate::CredentialStore;
+
+//HKDF "info" labels of the MAC keys of the DB and of the generation in the
+//master key file
+const VAULT_MAC_INFO: &[u8] = b"pwmgr vault mac";
+const GENERATION_MAC_INFO: &[u8] = b"pwmgr key file generation mac";
+
+//Outcome of checking the integrity of the credential DB
+#[derive(Debug, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    //Neither the DB nor the master key file has a MAC (written by an older
+    //version, or stripped)
+    Unprotected,
+    //The DB or the master key file lacks the MAC the other one has 
+    //(stripped, or left so by an older version or an interrupted save)
+    Incomplete(&'static str),
+    //The MAC verifies, and the DB is the last one saved
+    Current,
+    //The MAC verifies, but the DB is an older copy than the last one saved
+    RolledBack { generation: u64, expected: u64 },
+}
+
+//Credentials sorted by site and user, so that they always serialize alike
+fn canonical_serialization(db: &CredentialStore)
+   -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let sorted: BTreeMap<&String, BTreeMap<&String, &Credential>> = db.iter()
+        .map(|(site, site_users)| (site, site_users.iter().collect()))
+        .collect();
+    Ok(Zeroizing::new(serde_json::to_vec(&sorted)?))
+}
+
+//HMAC keyed with the key derived from the vault key for `info`
+fn keyed_mac(vault_key: &SecretKey, info: &[u8]) -> Hmac<Sha256> {
+    let hkdf = Hkdf::<Sha256>::new(None, vault_key.expose());
+    let mut mac_key = Zeroizing::new([0u8; 32]);
+    hkdf.expand(info, &mut *mac_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    <Hmac<Sha256> as Mac>::new_from_slice(&*mac_key)
+        .expect("HMAC accepts keys of any length")
+}
+
+fn mac(db: &CredentialStore, generation: u64, vault_key: &SecretKey)
+   -> Result<Hmac<Sha256>, Box<dyn Error>> {
+    let mut mac = keyed_mac(vault_key, VAULT_MAC_INFO);
+    mac.update(b"pwmgr vault mac v1");
+    mac.update(&generation.to_be_bytes());
+    mac.update(&canonical_serialization(db)?);
+    Ok(mac)
+}
+
+/// Computes the integrity record of `db` saved as `generation`
+pub fn vault_mac(db: &CredentialStore, generation: u64, vault_key: &SecretKey)
+       -> Result<VaultIntegrity, Box<dyn Error>> {
+    Ok(VaultIntegrity {
+        generation,
+        mac: STANDARD.encode(mac(db, generation, vault_key)?.finalize()
+                             .into_bytes()),
+    })
+}
+
+fn generation_mac(generation: u64, vault_key: &SecretKey) -> Hmac<Sha256> {
+    let mut mac = keyed_mac(vault_key, GENERATION_MAC_INFO);
+    mac.update(b"pwmgr key file generation v1");
+    mac.update(&generation.to_be_bytes());
+    mac
+}
+
+/// Records `generation` as the last one saved in `key_file`, with its MAC
+pub fn set_saved_generation(key_file: &mut MasterKeyFile, generation: u64,
+                            vault_key: &SecretKey) {
+    key_file.generation = Some(generation);
+    key_file.generation_mac = Some(STANDARD.encode(
+        generation_mac(generation, vault_key).finalize().into_bytes()));
+}
+
+/// Checks `db` against its integrity record, if any, and against the 
+/// generation `key_file` remembers. Fails if the DB or the generation was
+/// tampered with.
+pub fn check(db: &CredentialStore, integrity: Option<&VaultIntegrity>,
+             key_file: &MasterKeyFile, vault_key: &SecretKey)
+       -> Result<IntegrityCheck, Box<dyn Error>> {
+    //A generation without its MAC does not count, but the DB is still
+    //checked against its own MAC
+    const UNAUTHENTICATED: &str = 
+        "the generation in the master key file has no MAC";
+    let (expected_generation, unauthenticated) = 
+        match (key_file.generation, &key_file.generation_mac) {
+        (Some(generation), Some(tag)) => {
+            let verified = STANDARD.decode(tag).is_ok_and(|tag|
+                generation_mac(generation, vault_key).verify_slice(&tag)
+                    .is_ok());
+            if !verified {
+                return Err("Tamper detected: the generation in the master \
+                            key file does not match its MAC".into());
+            }
+            (Some(generation), false)
+        },
+        (None, Some(_)) => return Err("Tamper detected: the master key file \
+            has a generation MAC, but no generation".into()),
+        (Some(_), None) => (None, true),
+        (None, None) => (None, false),
+    };
+    let Some(integrity) = integrity else {
+        return Ok(match expected_generation {
+            _ if unauthenticated => IntegrityCheck::Incomplete(UNAUTHENTICATED),
+            Some(_) => IntegrityCheck::Incomplete(
+                "Credential DB has no integrity MAC, but one was saved with it"),
+            None => IntegrityCheck::Unprotected,
+        });
+    };
+    let verified = STANDARD.decode(&integrity.mac).is_ok_and(|tag|
+        mac(db, integrity.generation, vault_key)
+            .is_ok_and(|mac| mac.verify_slice(&tag).is_ok()));
+    if !verified {
+        return Err("Tamper detected: Credential DB does not match its \
+                    integrity MAC (credentials deleted, added or edited)"
+                   .into());
+    }
+    match expected_generation {
+        _ if unauthenticated => Ok(IntegrityCheck::Incomplete(UNAUTHENTICATED)),
+        None => Ok(IntegrityCheck::Incomplete(
+            "Credential DB has an integrity MAC, but the master key file has \
+             no generation saved with it")),
+        Some(expected) if integrity.generation < expected =>
+            Ok(IntegrityCheck::RolledBack {
+                generation: integrity.generation,
+                expected,
+            }),
+        Some(_) => Ok(IntegrityCheck::Current),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_kdf_params, new_master_key_file};
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_bytes(&mut [byte; 32]).unwrap()
+    }
+
+    fn db(password: &str) -> CredentialStore {
+        let mut db = CredentialStore::new();
+        db.entry("site".to_string()).or_default().insert("user".to_string(),
+            Credential {
+                username: "name".to_string(),
+                password: password.to_string(),
+                history: Vec::new(),
+            });
+        db
+    }
+
+    //A master key file remembering `generation`, if any
+    fn key_file(generation: Option<u64>) -> MasterKeyFile {
+        let mut key_file = new_master_key_file(&key(2), 
+            generate_kdf_params(8, 1, 1), &key(1)).unwrap();
+        if let Some(generation) = generation {
+            set_saved_generation(&mut key_file, generation, &key(1));
+        }
+        key_file
+    }
+
+    #[test]
+    fn unprotected_without_any_mac() {
+        assert_eq!(check(&db("a"), None, &key_file(None), &key(1)).unwrap(),
+                   IntegrityCheck::Unprotected);
+    }
+
+    #[test]
+    fn current_and_rolled_back() {
+        let record = vault_mac(&db("a"), 3, &key(1)).unwrap();
+        assert_eq!(check(&db("a"), Some(&record), &key_file(Some(3)), &key(1))
+                   .unwrap(), IntegrityCheck::Current);
+        assert_eq!(check(&db("a"), Some(&record), &key_file(Some(5)), &key(1))
+                   .unwrap(), 
+                   IntegrityCheck::RolledBack { generation: 3, expected: 5 });
+    }
+
+    #[test]
+    fn incomplete_when_either_side_lacks_its_mac() {
+        let record = vault_mac(&db("a"), 3, &key(1)).unwrap();
+        assert!(matches!(check(&db("a"), None, &key_file(Some(3)), &key(1)),
+                         Ok(IntegrityCheck::Incomplete(_))));
+        assert!(matches!(check(&db("a"), Some(&record), &key_file(None), 
+                               &key(1)),
+                         Ok(IntegrityCheck::Incomplete(_))));
+        let mut unauthenticated = key_file(Some(3));
+        unauthenticated.generation_mac = None;
+        assert!(matches!(check(&db("a"), Some(&record), &unauthenticated, 
+                               &key(1)),
+                         Ok(IntegrityCheck::Incomplete(_))));
+        //The DB is still checked against its own MAC
+        assert!(check(&db("b"), Some(&record), &unauthenticated, &key(1))
+                .is_err());
+    }
+
+    #[test]
+    fn tampering_is_an_error() {
+        let record = vault_mac(&db("a"), 3, &key(1)).unwrap();
+        assert!(check(&db("b"), Some(&record), &key_file(Some(3)), &key(1))
+                .is_err());
+        assert!(check(&db("a"), Some(&record), &key_file(Some(3)), &key(9))
+                .is_err());
+        let mut raised = key_file(Some(3));
+        raised.generation = Some(4);
+        assert!(check(&db("a"), Some(&record), &raised, &key(1)).is_err());
+        let mut no_generation = key_file(Some(3));
+        no_generation.generation = None;
+        assert!(check(&db("a"), Some(&record), &no_generation, &key(1))
+                .is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//Append-only journal of changes to the credentials.
+//
+//Every credential added, updated or deleted is recorded in
+//"<DB file>.journal", one encrypted entry per line, with the time of the
+//change and the credential before and after it. Undoing a change puts back
+//the credential it replaced, and is recorded as well, as is redoing it.
+//Each entry is authenticated along with its position in the journal, so
+//entries cannot be reordered or removed (except at the end) undetected.
+//A final line without its newline is what remains of an append cut short:
+//it is ignored, and cut off by the next append.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::secret::SecretKey;
+use crate::structs::{Credential, JournalEntry, JournalOp};
+use crate::throttle::now_secs;
+use crate::{decrypt_bytes, decrypt_credential, encrypt_bytes, CipherId, KdfId};
+
+const JOURNAL_AAD: &str = "pwmgr-journal";
+
+impl fmt::Display for JournalOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JournalOp::Add => "add",
+            JournalOp::Update => "update",
+            JournalOp::Delete => "delete",
+            JournalOp::Undo => "undo",
+            JournalOp::Redo => "redo",
+        })
+    }
+}
+
+/// Name of the journal of the vault whose DB is `db_file_name`
+pub fn journal_file_name(db_file_name: &str) -> String {
+    format!("{}.journal", db_file_name)
+}
+
+//Associated data of the entry at position `seq`
+fn entry_aad(seq: u64) -> Vec<u8> {
+    let mut aad = JOURNAL_AAD.as_bytes().to_vec();
+    aad.extend_from_slice(&seq.to_be_bytes());
+    aad
+}
+
+//Length of the complete lines of `contents`, up to its last newline
+fn complete_len(contents: &str) -> usize {
+    contents.rfind('\n').map_or(0, |end| end + 1)
+}
+
+//The journal of a vault, and the changes to be appended to it
+pub struct Journal {
+    file_name: String,
+    pending: Vec<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(db_file_name: &str) -> Journal {
+        Journal {
+            file_name: journal_file_name(db_file_name),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Reads and decrypts all entries, oldest first
+    pub fn entries(&self, vault_key: &SecretKey)
+           -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let contents = match fs::read_to_string(&self.file_name) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound =>
+                return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+        let mut entries = Vec::new();
+        let complete = &contents[..complete_len(&contents)];
+        for (index, line) in complete.lines().enumerate() {
+            let seq = index as u64 + 1;
+            let entry: JournalEntry = decrypt_bytes(line, vault_key,
+                                                    &entry_aad(seq))
+                .ok()
+                .and_then(|entry| serde_json::from_slice(&entry).ok())
+                .filter(|entry: &JournalEntry| entry.seq == seq)
+                .ok_or_else(|| format!("Journal entry {} does not \
+                    authenticate (tampered or corrupted)", seq))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Records a change, to be appended by append()
+    pub fn record(&mut self, op: JournalOp, site: &str, user: &str,
+                  previous: Option<Credential>, current: Option<Credential>,
+                  target: Option<u64>) {
+        self.pending.push(JournalEntry {
+            seq: 0,
+            timestamp: now_secs(),
+            op,
+            site: site.to_string(),
+            user: user.to_string(),
+            previous,
+            current,
+            target,
+        });
+    }
+
+    /// Appends the recorded changes to the journal, encrypted with
+    /// `vault_key`
+    pub fn append(&mut self, vault_key: &SecretKey, cipher_id: CipherId)
+           -> Result<(), Box<dyn Error>> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true)
+            .truncate(false).open(&self.file_name)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let complete = complete_len(&contents);
+        if complete < contents.len() {
+            file.set_len(complete as u64)?;
+        }
+        let mut seq = contents[..complete].lines().count() as u64;
+        let mut lines = String::new();
+        for entry in self.pending.iter_mut() {
+            seq += 1;
+            entry.seq = seq;
+            lines.push_str(&encrypt_bytes(&serde_json::to_vec(entry)?,
+                vault_key, cipher_id, KdfId::VaultKey, &entry_aad(seq))?);
+            lines.push('\n');
+        }
+        file.seek(SeekFrom::Start(complete as u64))?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_all()?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+//Changes that can be undone (most recent last) and redone (next one last)
+fn undo_redo_stacks(entries: &[JournalEntry]) -> (Vec<u64>, Vec<u64>) {
+    let (mut undo, mut redo) = (Vec::new(), Vec::new());
+    for entry in entries.iter() {
+        match entry.op {
+            JournalOp::Add | JournalOp::Update | JournalOp::Delete => {
+                undo.push(entry.seq);
+                redo.clear();
+            },
+            JournalOp::Undo => {
+                undo.pop();
+                redo.extend(entry.target);
+            },
+            JournalOp::Redo => {
+                redo.pop();
+                undo.extend(entry.target);
+            },
+        }
+    }
+    (undo, redo)
+}
+
+/// The change the next undo reverts
+pub fn undoable(entries: &[JournalEntry]) -> Option<&JournalEntry> {
+    let seq = undo_redo_stacks(entries).0.pop()?;
+    entries.get(seq as usize - 1)
+}
+
+/// The undone change the next redo applies again
+pub fn redoable(entries: &[JournalEntry]) -> Option<&JournalEntry> {
+    let seq = undo_redo_stacks(entries).1.pop()?;
+    entries.get(seq as usize - 1)
+}
+
+/// Whether two versions of the credential of `user` on `site` hold the
+/// same username and password (however they were encrypted)
+pub fn same_credential(site: &str, user: &str, a: Option<&Credential>,
+                       b: Option<&Credential>, vault_key: &SecretKey)
+       -> Result<bool, Box<dyn Error>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Ok(a.username == b.username &&
+            decrypt_credential(site, user, a, vault_key)? ==
+            decrypt_credential(site, user, b, vault_key)?),
+        (a, b) => Ok(a.is_none() && b.is_none()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seq: u64, op: JournalOp, target: Option<u64>) -> JournalEntry {
+        JournalEntry {
+            seq,
+            timestamp: 0,
+            op,
+            site: "site".to_string(),
+            user: "user".to_string(),
+            previous: None,
+            current: None,
+            target,
+        }
+    }
+
+    #[test]
+    fn undo_and_redo_move_changes_between_stacks() {
+        let mut entries = vec![
+            entry(1, JournalOp::Add, None),
+            entry(2, JournalOp::Update, None),
+            entry(3, JournalOp::Undo, Some(2)),
+            entry(4, JournalOp::Undo, Some(1)),
+        ];
+        assert_eq!(undo_redo_stacks(&entries), (vec![], vec![2, 1]));
+        entries.push(entry(5, JournalOp::Redo, Some(1)));
+        assert_eq!(undo_redo_stacks(&entries), (vec![1], vec![2]));
+        assert_eq!(undoable(&entries).map(|entry| entry.seq), Some(1));
+        assert_eq!(redoable(&entries).map(|entry| entry.seq), Some(2));
+    }
+
+    #[test]
+    fn new_change_discards_redo() {
+        let entries = vec![
+            entry(1, JournalOp::Add, None),
+            entry(2, JournalOp::Undo, Some(1)),
+            entry(3, JournalOp::Delete, None),
+        ];
+        assert_eq!(undo_redo_stacks(&entries), (vec![3], vec![]));
+        assert!(redoable(&entries).is_none());
+    }
+
+    #[test]
+    fn complete_len_ends_at_last_newline() {
+        assert_eq!(complete_len(""), 0);
+        assert_eq!(complete_len("a\nb\n"), 4);
+        assert_eq!(complete_len("a\nb\ntor"), 4);
+        assert_eq!(complete_len("torn"), 0);
+    }
+}
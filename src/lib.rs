@@ -4,17 +4,24 @@ use std::time::Duration;
 use std::time::Instant;
 
 use std::str;
+use std::str::FromStr;
+use std::fmt;
 use std::error::Error;
 use std::fs;
 //use std::fs::OpenOptions;
 //use std::io::{BufWriter};
 use std::collections::HashMap;
 use aes_gcm::{Aes256Gcm, Key, Nonce};
-use aes_gcm::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use serde::{Serialize, Deserialize};
 use rand::RngCore;
 use rand::rngs::OsRng;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use sha2::{Digest, Sha256};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use subtle::ConstantTimeEq;
 
 use crossterm:: {
     cursor,
@@ -23,8 +30,26 @@ use crossterm:: {
 };
 
 pub mod structs;
+pub mod secret;
+pub mod throttle;
+pub mod recovery;
+pub mod persist;
+pub mod lock;
+pub mod backup;
+pub mod storage;
+pub mod journal;
+pub mod integrity;
+pub mod migrate;
+pub mod import;
+pub mod importers;
 
-use crate::structs::Credential;
+use crate::structs::{Credential, KdfParams, KeySlot, MasterKeyFile, 
+                     PreviousPassword, SealedVault, SlotKind, VaultDocument};
+use crate::secret::{SecretKey, SecretString};
+use crate::migrate::Migration;
+use crate::import::{ImportSink, ImportSummary};
+use crate::importers::ImportFormat;
+use zeroize::{Zeroize, Zeroizing};
 
 //SiteUser is a Hashmap with key as a "user" of the site, and Credential 
 //as the value. This allows CredentialStore to store credentials of 
@@ -35,96 +60,988 @@ pub type SiteUser = HashMap<String, Credential>;
 //Credential Store is a hashmap keyed by "site", and stores SiteUser as value
 pub type CredentialStore = HashMap<String, SiteUser>;
 
-// Derives a 32-byte master key from the provided master password using SHA-256.
+//Default Argon2id cost parameters for new vaults
+//(64 MiB of memory, 3 passes, 4 lanes - RFC 9106 second recommended option)
+pub const DEFAULT_KDF_MEMORY_KIB: u32 = 64 * 1024;
+pub const DEFAULT_KDF_ITERATIONS: u32 = 3;
+pub const DEFAULT_KDF_PARALLELISM: u32 = 4;
+
+pub const KDF_ARGON2ID: &str = "argon2id";
+
+//Master key hash file contents, as read from disk.
+//Vaults created before Argon2id was introduced store just the base64 encoded
+//SHA-256 of the master password, and are migrated on the next unlock.
+pub enum MasterKeyRecord {
+    Legacy(Vec<u8>),
+    Current(Box<MasterKeyFile>),
+}
+
+/// Generates a fresh set of KDF parameters with a random 16-byte salt
+pub fn generate_kdf_params(memory_kib: u32, iterations: u32, parallelism: u32)
+       -> KdfParams {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    KdfParams {
+        algorithm: KDF_ARGON2ID.to_string(),
+        salt: STANDARD.encode(salt),
+        memory_kib,
+        iterations,
+        parallelism,
+        keyfile: false,
+    }
+}
+
+/// Reads a keyfile: any file, whose SHA-256 digest becomes the secret 
+/// combined with the master password in the KDF
+pub fn read_keyfile(file_name: &str) -> Result<SecretKey, Box<dyn Error>> {
+    let contents = Zeroizing::new(fs::read(file_name)
+        .map_err(|e| format!("Could not read keyfile {} ({})", file_name, e))?);
+    if contents.is_empty() {
+        return Err(format!("Keyfile {} is empty", file_name).into());
+    }
+    let mut digest = Sha256::digest(contents.as_slice());
+    Ok(SecretKey::from_bytes(digest.as_mut_slice())
+        .expect("SHA-256 digests are 32 bytes"))
+}
+
+/// Writes a new keyfile of 64 random bytes, readable only by the owner.
+/// Never overwrites an existing file.
+pub fn generate_keyfile(file_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(file_name)
+        .map_err(|e| format!("Could not create keyfile {} ({})", file_name, e))?;
+    let mut contents = Zeroizing::new([0u8; 64]);
+    OsRng.fill_bytes(contents.as_mut_slice());
+    file.write_all(contents.as_slice())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Derives a 32-byte master key from the provided master password using 
+// Argon2id with the salt and cost parameters in `kdf_params`.
 //
 // # Arguments
 //
-// * `master_password` - The user-supplied master password as a string slice.
+// * `master_password` - The user-supplied master password.
+// * `kdf_params` - Salt and cost parameters of the vault.
+// * `keyfile` - Digest of the keyfile (see read_keyfile), used only if 
+//   `kdf_params` says the vault requires one.
 //
 // # Returns "Result" of:
 //
-// OK(A 32-byte array suitable for use as an AES-256-GCM encryption key)
-// Error("Invalid Master Password")
+// OK(A 32-byte key suitable for use as an AES-256-GCM encryption key)
+// Error(Unsupported algorithm, invalid salt or invalid cost parameters,
+//       or missing keyfile)
 //
-pub fn derive_master_key(master_password:&str) -> [u8; 32] {
+pub fn derive_master_key(master_password: &SecretString, kdf_params: &KdfParams,
+                         keyfile: Option<&SecretKey>) 
+       -> Result<SecretKey, Box<dyn Error>> {
+    if kdf_params.algorithm != KDF_ARGON2ID {
+        return Err(format!("Unsupported KDF algorithm: {}", 
+                           kdf_params.algorithm).into());
+    }
+    let salt = STANDARD.decode(&kdf_params.salt)?;
+    let params = Params::new(kdf_params.memory_kib, kdf_params.iterations,
+                             kdf_params.parallelism, Some(32))
+        .map_err(|e| format!("Invalid KDF parameters: {}", e))?;
+    let argon2 = match (kdf_params.keyfile, keyfile) {
+        (false, _) => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        (true, Some(keyfile)) => Argon2::new_with_secret(keyfile.expose(), 
+                Algorithm::Argon2id, Version::V0x13, params)
+            .map_err(|e| format!("Invalid keyfile secret: {}", e))?,
+        (true, None) => return Err("Vault requires a keyfile".into()),
+    };
+
+    let mut master_key = SecretKey::zeroed();
+    argon2.hash_password_into(master_password.expose().as_bytes(), &salt, 
+                              master_key.expose_mut())
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(master_key)
+}
+
+// Derives the master key the way vaults created before Argon2id did:
+// a single unsalted SHA-256 of the master password.
+// Only used to unlock (and then migrate) such vaults.
+pub fn derive_legacy_master_key(master_password: &SecretString) -> SecretKey {
     let mut hasher = Sha256::new();
-    hasher.update(master_password.as_bytes());
-    let master_key = hasher.finalize().into();
-    //println!("derive_master_key: Returning Master key: {:?}", master_key);
-    master_key
+    hasher.update(master_password.expose().as_bytes());
+    let mut digest = hasher.finalize();
+    SecretKey::from_bytes(digest.as_mut_slice())
+        .expect("SHA-256 digests are 32 bytes")
+}
+
+/// Parses the contents of the master key hash file.
+/// Accepts both the current JSON format and the legacy bare base64 hash.
+pub fn parse_master_key_file(contents: &str) 
+       -> Result<MasterKeyRecord, Box<dyn Error>> {
+    if contents.trim_start().starts_with('{') {
+        let key_file: MasterKeyFile = serde_json::from_str(contents)?;
+        Ok(MasterKeyRecord::Current(Box::new(key_file)))
+    }
+    else {
+        Ok(MasterKeyRecord::Legacy(STANDARD.decode(contents.trim())?))
+    }
+}
+
+//HKDF "info" label of the password verifier
+const VERIFIER_INFO: &[u8] = b"pwmgr master password verifier";
+
+// Derives the password verifier from the master key with HKDF-SHA256.
+// The verifier lets us check the master password without storing the 
+// master key: HKDF is one-way, so the verifier cannot be turned back into 
+// the key that encrypts the credentials.
+pub fn derive_verifier(master_key: &SecretKey) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(None, master_key.expose());
+    let mut verifier = [0u8; 32];
+    hkdf.expand(VERIFIER_INFO, &mut verifier)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    verifier
+}
+
+//HKDF "info" label of the vault key check value
+const VAULT_KEY_CHECK_INFO: &[u8] = b"pwmgr vault key check";
+
+// Derives the check value of the vault key with HKDF-SHA256, which lets a 
+// vault key rebuilt without any password (from recovery shares) be verified
+pub fn derive_vault_key_check(vault_key: &SecretKey) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(None, vault_key.expose());
+    let mut check = [0u8; 32];
+    hkdf.expand(VAULT_KEY_CHECK_INFO, &mut check)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    check
 }
 
+/// Stores the check value of `vault_key` in `key_file`
+pub fn set_vault_key_check(key_file: &mut MasterKeyFile, vault_key: &SecretKey) {
+    key_file.vault_key_check = 
+        Some(STANDARD.encode(derive_vault_key_check(vault_key)));
+}
+
+/// Checks `vault_key` against the check value in `key_file`, in constant time
+pub fn verify_vault_key(key_file: &MasterKeyFile, vault_key: &SecretKey) 
+       -> Result<(), Box<dyn Error>> {
+    let expected = STANDARD.decode(key_file.vault_key_check.as_ref()
+        .ok_or("Master key file has no vault key check value")?)?;
+    let check = derive_vault_key_check(vault_key);
+    if bool::from(check.as_slice().ct_eq(expected.as_slice())) {
+        Ok(())
+    }
+    else {
+        Err("Key does not match this vault's key".into())
+    }
+}
+
+/// Generates a random 32-byte vault key, used to encrypt the credentials
+pub fn generate_vault_key() -> SecretKey {
+    let mut vault_key = SecretKey::zeroed();
+    OsRng.fill_bytes(vault_key.expose_mut());
+    vault_key
+}
+
+//Name of the key slot holding the master password of a new vault, or of a 
+//vault migrated from a single master password
+pub const DEFAULT_SLOT_NAME: &str = "master";
+
+/// Unwraps the vault key with the master key: from key slot `slot_id`, or
+/// from the single master key of files written before key slots existed
+pub fn unwrap_vault_key(key_file: &MasterKeyFile, slot_id: Option<u32>,
+                        master_key: &SecretKey) 
+       -> Result<SecretKey, Box<dyn Error>> {
+    let wrapped_key = match slot_id {
+        Some(slot_id) => &find_key_slot(key_file, slot_id)?.wrapped_key,
+        None => key_file.wrapped_key.as_ref()
+            .ok_or("Master key file has no wrapped vault key")?,
+    };
+    let mut vault_key = decrypt_bytes(wrapped_key, master_key, &[])
+        .map_err(|e| format!("Could not unwrap vault key ({})", e))?;
+    SecretKey::from_bytes(&mut vault_key)
+        .map_err(|_| "Unwrapped vault key has invalid length".into())
+}
+
+/// Returns key slot `slot_id` of `key_file`
+pub fn find_key_slot(key_file: &MasterKeyFile, slot_id: u32) 
+       -> Result<&KeySlot, Box<dyn Error>> {
+    key_file.slots.iter().find(|slot| slot.id == slot_id)
+        .ok_or_else(|| format!("No key slot {}", slot_id).into())
+}
+
+//Returns key slot `slot_id` of `key_file`, for modification
+fn find_key_slot_mut(key_file: &mut MasterKeyFile, slot_id: u32) 
+   -> Result<&mut KeySlot, Box<dyn Error>> {
+    key_file.slots.iter_mut().find(|slot| slot.id == slot_id)
+        .ok_or_else(|| format!("No key slot {}", slot_id).into())
+}
+
+//Wraps `vault_key` with `master_key` for storing in a key slot
+fn wrap_vault_key(vault_key: &SecretKey, master_key: &SecretKey, 
+                  cipher_id: CipherId) -> Result<String, Box<dyn Error>> {
+    encrypt_bytes(vault_key.expose(), master_key, cipher_id, 
+                  KdfId::Argon2id, &[])
+}
+
+/// Builds the master key hash file of a new vault, with a single key slot
+/// for `master_key` derived with `kdf_params`
+pub fn new_master_key_file(master_key: &SecretKey, kdf_params: KdfParams,
+                           vault_key: &SecretKey) 
+       -> Result<MasterKeyFile, Box<dyn Error>> {
+    let mut key_file = MasterKeyFile {
+        slots: Vec::new(),
+        kdf: None,
+        verifier: None,
+        wrapped_key: None,
+        entries_bound: true,
+        sealed: false,
+        lockout_threshold: None,
+        throttle: None,
+        cipher: CipherId::default(),
+        vault_key_check: None,
+        recovery_kit: false,
+        backup_generations: None,
+        generation: None,
+        generation_mac: None,
+        master_key_hash: None,
+    };
+    add_key_slot(&mut key_file, DEFAULT_SLOT_NAME, master_key, kdf_params, 
+                 vault_key)?;
+    set_vault_key_check(&mut key_file, vault_key);
+    Ok(key_file)
+}
+
+/// Adds a key slot named `name` for `master_key` derived with `kdf_params`,
+/// wrapping `vault_key` with the vault's cipher. Returns the new slot's id.
+pub fn add_key_slot(key_file: &mut MasterKeyFile, name: &str, 
+                    master_key: &SecretKey, kdf_params: KdfParams, 
+                    vault_key: &SecretKey) 
+       -> Result<u32, Box<dyn Error>> {
+    push_key_slot(key_file, name, SlotKind::Password, master_key, kdf_params,
+                  vault_key)
+}
+
+//Argon2id cost of recovery code slots. Recovery codes are random, so a low
+//cost suffices, and keeps unlocking with a code fast with many codes left.
+const RECOVERY_CODE_KDF_MEMORY_KIB: u32 = 8 * 1024;
+const RECOVERY_CODE_KDF_ITERATIONS: u32 = 1;
+const RECOVERY_CODE_KDF_PARALLELISM: u32 = 1;
+
+/// Adds a key slot unlocked once by recovery `code` (see 
+/// recovery::generate_recovery_codes), wrapping `vault_key`. 
+/// Returns the new slot's id.
+pub fn add_recovery_code_slot(key_file: &mut MasterKeyFile, code: &SecretString,
+                              vault_key: &SecretKey) 
+       -> Result<u32, Box<dyn Error>> {
+    let kdf_params = generate_kdf_params(RECOVERY_CODE_KDF_MEMORY_KIB, 
+        RECOVERY_CODE_KDF_ITERATIONS, RECOVERY_CODE_KDF_PARALLELISM);
+    let master_key = derive_master_key(code, &kdf_params, None)?;
+    push_key_slot(key_file, "recovery code", SlotKind::RecoveryCode, 
+                  &master_key, kdf_params, vault_key)
+}
+
+//Adds a key slot of `kind`, returns its id
+fn push_key_slot(key_file: &mut MasterKeyFile, name: &str, kind: SlotKind,
+                 master_key: &SecretKey, kdf_params: KdfParams, 
+                 vault_key: &SecretKey) 
+   -> Result<u32, Box<dyn Error>> {
+    let id = key_file.slots.iter().map(|slot| slot.id + 1).max().unwrap_or(0);
+    key_file.slots.push(KeySlot {
+        id,
+        name: name.to_string(),
+        kind,
+        kdf: kdf_params,
+        verifier: STANDARD.encode(derive_verifier(master_key)),
+        wrapped_key: wrap_vault_key(vault_key, master_key, key_file.cipher)?,
+    });
+    Ok(id)
+}
 
-/// Verifies the master password against the stored master key hash.
+/// Replaces the master key of key slot `slot_id`: stores the KDF parameters
+/// and verifier of `master_key`, and re-wraps `vault_key` with it using the 
+/// vault's cipher.
+/// Other slots and vault settings in `key_file` are kept.
+pub fn set_master_key(key_file: &mut MasterKeyFile, slot_id: u32,
+                      master_key: &SecretKey, kdf_params: KdfParams, 
+                      vault_key: &SecretKey) 
+       -> Result<(), Box<dyn Error>> {
+    let wrapped_key = wrap_vault_key(vault_key, master_key, key_file.cipher)?;
+    let slot = find_key_slot_mut(key_file, slot_id)?;
+    slot.wrapped_key = wrapped_key;
+    slot.verifier = STANDARD.encode(derive_verifier(master_key));
+    slot.kdf = kdf_params;
+    Ok(())
+}
+
+/// Re-wraps `vault_key` in key slot `slot_id` with the vault's cipher, 
+/// keeping the slot's master key
+pub fn rewrap_vault_key(key_file: &mut MasterKeyFile, slot_id: u32,
+                        master_key: &SecretKey, vault_key: &SecretKey) 
+       -> Result<(), Box<dyn Error>> {
+    let wrapped_key = wrap_vault_key(vault_key, master_key, key_file.cipher)?;
+    find_key_slot_mut(key_file, slot_id)?.wrapped_key = wrapped_key;
+    Ok(())
+}
+
+/// Removes key slot `slot_id`, so its password (or recovery code) no longer
+/// unlocks the vault. The last password slot cannot be revoked.
+pub fn revoke_key_slot(key_file: &mut MasterKeyFile, slot_id: u32) 
+       -> Result<KeySlot, Box<dyn Error>> {
+    let index = key_file.slots.iter().position(|slot| slot.id == slot_id)
+        .ok_or_else(|| format!("No key slot {}", slot_id))?;
+    let password_slots = key_file.slots.iter()
+        .filter(|slot| slot.kind == SlotKind::Password)
+        .count();
+    if key_file.slots[index].kind == SlotKind::Password && password_slots == 1 {
+        return Err("Cannot revoke the last password key slot".into());
+    }
+    Ok(key_file.slots.remove(index))
+}
+
+/// Moves the single master key of a file written before key slots existed
+/// into a new key slot, wrapping `vault_key` with it. Returns the slot's id.
+pub fn move_master_key_to_slot(key_file: &mut MasterKeyFile, 
+                               master_key: &SecretKey, vault_key: &SecretKey)
+       -> Result<u32, Box<dyn Error>> {
+    let kdf_params = key_file.kdf.take()
+        .ok_or("Master key file has no KDF parameters")?;
+    let slot_id = add_key_slot(key_file, DEFAULT_SLOT_NAME, master_key, 
+                               kdf_params, vault_key)?;
+    key_file.verifier = None;
+    key_file.wrapped_key = None;
+    key_file.master_key_hash = None;
+    Ok(slot_id)
+}
+
+//Why unlocking the vault failed
+#[derive(Debug)]
+pub enum AuthError {
+    //The master password does not match the stored verifier
+    WrongPassword,
+    //The vault requires a keyfile, but none was given
+    MissingKeyfile,
+    //The verifier (or stored hash) in the master key file is missing or 
+    //malformed, so no password can match it
+    CorruptVerifier(String),
+    //The master key could not be derived from the password, e.g. because of
+    //an unsupported KDF algorithm or invalid KDF parameters
+    KeyDerivation(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::WrongPassword => 
+                write!(f, "Invalid Master Password"),
+            AuthError::MissingKeyfile => 
+                write!(f, "This vault requires a keyfile, pass it with --keyfile"),
+            AuthError::CorruptVerifier(reason) => 
+                write!(f, "Master key file is corrupt: {}", reason),
+            AuthError::KeyDerivation(reason) => 
+                write!(f, "Could not derive master key: {}", reason),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+//Decodes a stored verifier or master key hash, which must be 32 bytes
+fn decode_verifier(encoded: &str) -> Result<Vec<u8>, AuthError> {
+    let decoded = STANDARD.decode(encoded)
+        .map_err(|e| AuthError::CorruptVerifier(e.to_string()))?;
+    if decoded.len() != 32 {
+        return Err(AuthError::CorruptVerifier(
+            format!("verifier is {} bytes, expected 32", decoded.len())));
+    }
+    Ok(decoded)
+}
+
+//A successfully unlocked vault
+pub struct Unlocked {
+    pub master_key: SecretKey,
+    //Key slot the password matched, None for files written before key 
+    //slots existed
+    pub slot_id: Option<u32>,
+}
+
+/// Verifies the master password (and keyfile, if required) against the 
+/// verifier of every key slot of `kind` (or, for files written by older 
+/// versions, the stored verifier or master key hash).
+/// The comparisons run in constant time.
 pub fn verify_master_password(
-        master_password: &str, 
-        master_key_hash: &[u8]) -> 
-        Result<[u8; 32], String> {
+        master_password: &SecretString, 
+        keyfile: Option<&SecretKey>,
+        kind: SlotKind,
+        master_key_record: &MasterKeyRecord) -> 
+        Result<Unlocked, AuthError> {
     
-    let master_key = derive_master_key(master_password);
-    //if master_key == MASTER_KEY_HASH {
-    if master_key == master_key_hash {
-        Ok(master_key)
+    //`check` is what gets compared against the stored `expected` value:
+    //the verifier, or the key itself for files written by older versions
+    let (master_key, expected, check) = match master_key_record {
+        MasterKeyRecord::Current(key_file) if !key_file.slots.is_empty() => {
+            return verify_key_slots(master_password, keyfile, kind, 
+                                    &key_file.slots);
+        },
+        //Older files have nothing but the master password
+        _ if kind != SlotKind::Password => return Err(AuthError::WrongPassword),
+        MasterKeyRecord::Legacy(hash) => {
+            if hash.len() != 32 {
+                return Err(AuthError::CorruptVerifier(
+                    format!("hash is {} bytes, expected 32", hash.len())));
+            }
+            let master_key = derive_legacy_master_key(master_password);
+            let check = Zeroizing::new(master_key.expose().to_vec());
+            (master_key, hash.clone(), check)
+        },
+        MasterKeyRecord::Current(key_file) => {
+            let expected = match (&key_file.verifier, &key_file.master_key_hash) {
+                (Some(verifier), _) => decode_verifier(verifier)?,
+                (None, Some(hash)) => decode_verifier(hash)?,
+                (None, None) => return Err(AuthError::CorruptVerifier(
+                    "no verifier".to_string())),
+            };
+            let kdf_params = key_file.kdf.as_ref().ok_or_else(|| 
+                AuthError::CorruptVerifier("no KDF parameters".to_string()))?;
+            if kdf_params.keyfile && keyfile.is_none() {
+                return Err(AuthError::MissingKeyfile);
+            }
+            let master_key = 
+                derive_master_key(master_password, kdf_params, keyfile)
+                .map_err(|e| AuthError::KeyDerivation(e.to_string()))?;
+            let check = if key_file.verifier.is_some() {
+                Zeroizing::new(derive_verifier(&master_key).to_vec())
+            }
+            else {
+                Zeroizing::new(master_key.expose().to_vec())
+            };
+            (master_key, expected, check)
+        },
+    };
+    if bool::from(check.as_slice().ct_eq(expected.as_slice())) {
+        Ok(Unlocked { master_key, slot_id: None })
     }
     else {
-        Err("Invalid Master Password".to_string())
+        Err(AuthError::WrongPassword)
     }
 }
 
-/// Generates a random 12-byte nonce for AES-GCM encryption
-pub fn generate_nonce() -> [u8; 12] {
-    let mut nonce_bytes = [0u8; 12];
+/// Tries the master password on each key slot of `kind` in turn, until one
+/// matches. Slots requiring a keyfile are skipped when none is given, and a
+/// corrupt slot does not keep the other slots from unlocking.
+pub fn verify_key_slots(master_password: &SecretString, 
+                        keyfile: Option<&SecretKey>, kind: SlotKind,
+                        slots: &[KeySlot]) 
+       -> Result<Unlocked, AuthError> {
+    let mut tried = false;
+    let mut skipped_for_keyfile = false;
+    let mut first_error = None;
+    for slot in slots.iter().filter(|slot| slot.kind == kind) {
+        if slot.kdf.keyfile && keyfile.is_none() {
+            skipped_for_keyfile = true;
+            continue;
+        }
+        let expected = match decode_verifier(&slot.verifier) {
+            Ok(expected) => expected,
+            Err(error) => {
+                first_error.get_or_insert(error);
+                continue;
+            }
+        };
+        let master_key = 
+            match derive_master_key(master_password, &slot.kdf, keyfile) {
+            Ok(master_key) => master_key,
+            Err(error) => {
+                first_error.get_or_insert(
+                    AuthError::KeyDerivation(error.to_string()));
+                continue;
+            }
+        };
+        tried = true;
+        let check = derive_verifier(&master_key);
+        if bool::from(check.ct_eq(expected.as_slice())) {
+            return Ok(Unlocked { master_key, slot_id: Some(slot.id) });
+        }
+    }
+    match first_error {
+        _ if tried => Err(AuthError::WrongPassword),
+        Some(error) => Err(error),
+        None if skipped_for_keyfile => Err(AuthError::MissingKeyfile),
+        None => Err(AuthError::WrongPassword),
+    }
+}
+
+/// Re-encrypts every password (current and previous) in `db` from `old_key` 
+/// to `new_key` with `cipher_id`, binding each entry to its site, user and 
+/// username.
+/// `old_entries_bound` tells whether the existing entries are already bound
+/// (vaults written by older versions encrypt without associated data).
+/// Either all entries are re-encrypted or `db` is left untouched.
+pub fn reencrypt_db(db: &mut CredentialStore, 
+                    old_key: &SecretKey, old_entries_bound: bool,
+                    new_key: &SecretKey, cipher_id: CipherId) 
+       -> Result<(), Box<dyn Error>> {
+    let mut reencrypted = Vec::new();
+    for (site, site_users) in db.iter() {
+        for (user, cred) in site_users.iter() {
+            let plaintext = if old_entries_bound {
+                decrypt_credential(site, user, cred, old_key)
+            }
+            else {
+                decrypt(&cred.password, old_key, &[])
+            }
+            .map_err(|e| format!(
+                "Error decrypting password {} user: {}, site: {}", 
+                e, user, site))?;
+            let ciphertext = encrypt(plaintext.expose(), new_key, cipher_id,
+                                     &credential_aad(site, user, &cred.username))
+                .map_err(|e| format!(
+                    "Error re-encrypting password {} user: {}, site: {}", 
+                    e, user, site))?;
+            //Previous passwords only exist in bound vaults
+            let mut history = Vec::new();
+            for previous in cred.history.iter() {
+                let plaintext = 
+                    decrypt_previous_password(site, user, previous, old_key)?;
+                let aad = previous_password_aad(site, user, 
+                    &previous.username, previous.replaced);
+                history.push(PreviousPassword {
+                    password: encrypt(plaintext.expose(), new_key, cipher_id,
+                                      &aad)?,
+                    ..previous.clone()
+                });
+            }
+            reencrypted.push((site.clone(), user.clone(), ciphertext, history));
+        }
+    }
+
+    for (site, user, ciphertext, history) in reencrypted {
+        if let Some(cred) = db.get_mut(&site).and_then(|s| s.get_mut(&user)) {
+            cred.password = ciphertext;
+            cred.history = history;
+        }
+    }
+    Ok(())
+}
+
+//Version of the associated data layout below. Part of the associated data,
+//so a blob can never be authenticated under a different layout.
+pub const AAD_SCHEMA_VERSION: u32 = 1;
+
+// Builds the AES-GCM associated data binding a credential's ciphertext to
+// its site, user and username. Each field is length-prefixed, so 
+// ("ab", "c") and ("a", "bc") produce different associated data.
+pub fn credential_aad(site: &str, user: &str, username: &str) -> Vec<u8> {
+    let mut aad = Vec::new();
+    aad.extend_from_slice(b"pwmgr credential");
+    aad.extend_from_slice(&AAD_SCHEMA_VERSION.to_be_bytes());
+    for field in [site, user, username] {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field.as_bytes());
+    }
+    aad
+}
+
+/// Encrypts `password` with `cipher_id` into a Credential bound to 
+/// `site` and `user`
+pub fn encrypt_credential(site: &str, user: &str, username: String, 
+                          password: &SecretString, key: &SecretKey, 
+                          cipher_id: CipherId) 
+       -> Result<Credential, Box<dyn Error>> {
+    let aad = credential_aad(site, user, &username);
+    Ok(Credential {
+        password: encrypt(password.expose(), key, cipher_id, &aad)?,
+        username,
+        history: Vec::new(),
+    })
+}
+
+// Associated data of a previous password of a credential: that of the 
+// credential it was the password of, and the time it was replaced. A 
+// previous password can thus neither pass for a current one, nor be 
+// backdated.
+pub fn previous_password_aad(site: &str, user: &str, username: &str, 
+                             replaced: u64) -> Vec<u8> {
+    let mut aad = credential_aad(site, user, username);
+    aad.extend_from_slice(b"previous password");
+    aad.extend_from_slice(&replaced.to_be_bytes());
+    aad
+}
+
+/// Moves the password of `old_cred` (and its history) into the history of 
+/// `new_cred`, its replacement as the credential of `user` on `site`
+pub fn keep_previous_password(site: &str, user: &str, old_cred: Credential, 
+                              new_cred: &mut Credential, key: &SecretKey, 
+                              cipher_id: CipherId, replaced: u64) 
+       -> Result<(), Box<dyn Error>> {
+    let plaintext = decrypt_credential(site, user, &old_cred, key)?;
+    let aad = previous_password_aad(site, user, &old_cred.username, replaced);
+    new_cred.history = old_cred.history;
+    new_cred.history.push(PreviousPassword {
+        password: encrypt(plaintext.expose(), key, cipher_id, &aad)?,
+        username: old_cred.username,
+        replaced,
+    });
+    Ok(())
+}
+
+/// Decrypts a previous password of the Credential stored under `site` and 
+/// `user`
+pub fn decrypt_previous_password(site: &str, user: &str, 
+                                 previous: &PreviousPassword, key: &SecretKey)
+       -> Result<SecretString, Box<dyn Error>> {
+    let aad = previous_password_aad(site, user, &previous.username, 
+                                    previous.replaced);
+    decrypt(&previous.password, key, &aad)
+        .map_err(|_| format!(
+            "Tamper detected: previous password for Site: {:?} User: {:?} \
+            does not authenticate (entry moved, edited or corrupted)", 
+            site, user).into())
+}
+
+/// Decrypts the password of the Credential stored under `site` and `user`.
+/// Fails with a tamper error if the ciphertext does not belong to this entry,
+/// e.g. because it was copied over from another site or user.
+pub fn decrypt_credential(site: &str, user: &str, cred: &Credential, 
+                          key: &SecretKey) 
+       -> Result<SecretString, Box<dyn Error>> {
+    let aad = credential_aad(site, user, &cred.username);
+    decrypt(&cred.password, key, &aad)
+        .map_err(|_| format!(
+            "Tamper detected: password for Site: {:?} User: {:?} does not \
+            authenticate (entry moved, edited or corrupted)", site, user).into())
+}
+
+/// Generates a random nonce of `len` bytes
+pub fn generate_nonce(len: usize) -> Vec<u8> {
+    let mut nonce_bytes = vec![0u8; len];
     OsRng.fill_bytes(&mut nonce_bytes);
     nonce_bytes
 }
 
-/// Encrypts data using AES-256-GCM
-/// Returns a vector containing: [nonce (12 bytes) + ciphertext]
+//Encrypted blobs are self-describing envelopes:
+//    "$pwmgr$" + Base64 of: [version (1 byte) + cipher id (1 byte) + 
+//                            KDF id (1 byte) + nonce + ciphertext]
+//The version, cipher id and KDF id are authenticated along with the 
+//caller's associated data.
+//Blobs written by older versions are a bare Base64 of: 
+//    [nonce (12 bytes) + AES-256-GCM ciphertext]
+//and can still be decrypted.
+pub const ENVELOPE_PREFIX: &str = "$pwmgr$";
+pub const ENVELOPE_VERSION: u8 = 1;
+
+//Cipher used to encrypt an envelope. Each vault has one, recorded in the 
+//master key file, which is used for everything the vault encrypts.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherId {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm = 1,
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305 = 2,
+}
+
+impl CipherId {
+    /// Returns the implementation of this cipher
+    pub fn cipher(self) -> &'static dyn Cipher {
+        match self {
+            CipherId::Aes256Gcm => &Aes256GcmCipher,
+            CipherId::XChaCha20Poly1305 => &XChaCha20Poly1305Cipher,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherId::Aes256Gcm => "aes-256-gcm",
+            CipherId::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+}
+
+impl fmt::Display for CipherId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CipherId {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "aes-256-gcm" => Ok(CipherId::Aes256Gcm),
+            "xchacha20-poly1305" => Ok(CipherId::XChaCha20Poly1305),
+            _ => Err(format!(
+                "Unknown cipher {} (expected aes-256-gcm or xchacha20-poly1305)", 
+                name)),
+        }
+    }
+}
+
+impl TryFrom<u8> for CipherId {
+    type Error = String;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(CipherId::Aes256Gcm),
+            2 => Ok(CipherId::XChaCha20Poly1305),
+            _ => Err(format!("Unknown cipher id {}", id)),
+        }
+    }
+}
+
+//An AEAD cipher with a 256-bit key, usable for envelopes
+pub trait Cipher {
+    fn id(&self) -> CipherId;
+
+    //Length of the nonce in bytes. Nonces are random, so this also bounds
+    //how many blobs can safely be encrypted under one key.
+    fn nonce_len(&self) -> usize;
+
+    fn encrypt(&self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8])
+       -> Result<Vec<u8>, Box<dyn Error>>;
+
+    fn decrypt(&self, key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], 
+               aad: &[u8])
+       -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+//AES-256-GCM with 96-bit nonces
+pub struct Aes256GcmCipher;
+
+impl Cipher for Aes256GcmCipher {
+    fn id(&self) -> CipherId {
+        CipherId::Aes256Gcm
+    }
+
+    fn nonce_len(&self) -> usize {
+        12
+    }
+
+    fn encrypt(&self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8])
+       -> Result<Vec<u8>, Box<dyn Error>> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        cipher.encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|e| format!("Encryption failed: {}", e).into())
+    }
+
+    fn decrypt(&self, key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], 
+               aad: &[u8])
+       -> Result<Vec<u8>, Box<dyn Error>> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|e| format!("Decryption failed: {}", e).into())
+    }
+}
+
+//XChaCha20-Poly1305 with 192-bit nonces, which are safe to pick at random
+//for any realistic number of blobs
+pub struct XChaCha20Poly1305Cipher;
+
+impl Cipher for XChaCha20Poly1305Cipher {
+    fn id(&self) -> CipherId {
+        CipherId::XChaCha20Poly1305
+    }
+
+    fn nonce_len(&self) -> usize {
+        24
+    }
+
+    fn encrypt(&self, key: &[u8; 32], nonce: &[u8], data: &[u8], aad: &[u8])
+       -> Result<Vec<u8>, Box<dyn Error>> {
+        let cipher = XChaCha20Poly1305::new(key.into());
+        cipher.encrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|e| format!("Encryption failed: {}", e).into())
+    }
+
+    fn decrypt(&self, key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], 
+               aad: &[u8])
+       -> Result<Vec<u8>, Box<dyn Error>> {
+        let cipher = XChaCha20Poly1305::new(key.into());
+        cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|e| format!("Decryption failed: {}", e).into())
+    }
+}
+
+//Where the key that encrypts an envelope comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KdfId {
+    //Random vault key, used as is
+    VaultKey = 0,
+    //Master key, derived from the master password with Argon2id
+    Argon2id = 1,
+}
+
+impl TryFrom<u8> for KdfId {
+    type Error = String;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0 => Ok(KdfId::VaultKey),
+            1 => Ok(KdfId::Argon2id),
+            _ => Err(format!("Unknown KDF id {}", id)),
+        }
+    }
+}
+
+// Associated data of an envelope: its header followed by the caller's 
+// associated data
+fn envelope_aad(version: u8, cipher_id: CipherId, kdf_id: KdfId, aad: &[u8]) 
+   -> Vec<u8> {
+    let mut envelope_aad = vec![version, cipher_id as u8, kdf_id as u8];
+    envelope_aad.extend_from_slice(aad);
+    envelope_aad
+}
+
+/// Encrypts data with the vault key, authenticating `aad` along with it
+/// Returns an envelope (see ENVELOPE_PREFIX)
 pub fn encrypt(
-               data: &str, key: &[u8; 32]) 
+               data: &str, key: &SecretKey, cipher_id: CipherId, aad: &[u8]) 
+               -> Result<String, Box<dyn Error>> {
+    encrypt_bytes(data.as_bytes(), key, cipher_id, KdfId::VaultKey, aad)
+}
+
+/// Encrypts raw bytes with `cipher_id`, using a key obtained through `kdf_id`
+/// Returns an envelope (see ENVELOPE_PREFIX)
+pub fn encrypt_bytes(
+               data: &[u8], key: &SecretKey, cipher_id: CipherId, 
+               kdf_id: KdfId, aad: &[u8]) 
                -> Result<String, Box<dyn Error>> {
 
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-    let nonce_bytes = generate_nonce();
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    let ciphertext = cipher.encrypt(nonce, data.as_bytes())
-        .map_err(|e| format!("Encryption failed: {}", e))?;
+    let cipher = cipher_id.cipher();
+    let nonce = generate_nonce(cipher.nonce_len());
+    let aad = envelope_aad(ENVELOPE_VERSION, cipher_id, kdf_id, aad);
+    let ciphertext = cipher.encrypt(key.expose(), &nonce, data, &aad)?;
 
-    //Concatenate nonce and ciphertext
-    let mut result = Vec::new();
-    result.extend_from_slice(&nonce_bytes);
+    //Concatenate header, nonce and ciphertext
+    let mut result = vec![ENVELOPE_VERSION, cipher_id as u8, kdf_id as u8];
+    result.extend_from_slice(&nonce);
     result.extend_from_slice(&ciphertext);
 
     //Perform base64 encoding
-    let encoded_result = STANDARD.encode(&result);
+    let encoded_result = format!("{}{}", ENVELOPE_PREFIX, STANDARD.encode(&result));
     Ok(encoded_result)
 }
 
-// Decrypts data using AES-256-GCM
-// Input is Base64 encoded encrypted text: [nonce (12 bytes) + ciphertext]
+// Decrypts data, `aad` must match the associated data given to encrypt()
+// Input is an envelope, or a blob written by older versions
 pub fn decrypt(
-            encrypted_data: &str, key: &[u8; 32])
-            ->Result<String, Box<dyn Error>> {
+            encrypted_data: &str, key: &SecretKey, aad: &[u8])
+            ->Result<SecretString, Box<dyn Error>> {
+    let mut plaintext = decrypt_bytes(encrypted_data, key, aad)?;
+    match String::from_utf8(std::mem::take(&mut *plaintext)) {
+        Ok(plaintext) => Ok(SecretString::from(plaintext)),
+        Err(error) => {
+            let msg = format!("Invalid UTF-8: {}", error.utf8_error());
+            error.into_bytes().zeroize();
+            Err(msg.into())
+        }
+    }
+}
+
+// Decrypts raw bytes, dispatching on the envelope version and cipher id
+// Input is an envelope, or a blob written by older versions
+// The plaintext is wiped when the returned buffer is dropped
+pub fn decrypt_bytes(
+            encrypted_data: &str, key: &SecretKey, aad: &[u8])
+            ->Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
 
-    //Decode the Base64 encoded text
-    let decoded_data = match STANDARD.decode(encrypted_data) {
-        Ok(data) => data,
-        Err(error) => return Err(Box::new(error))
+    let Some(envelope) = encrypted_data.strip_prefix(ENVELOPE_PREFIX) else {
+        //Older blob: [nonce (12 bytes) + AES-256-GCM ciphertext]
+        return open_with(&Aes256GcmCipher, 
+                         &STANDARD.decode(encrypted_data)?, key, aad);
     };
 
-    if decoded_data.len() < 12 {
+    //Decode the Base64 encoded envelope
+    let decoded_data = STANDARD.decode(envelope)?;
+    if decoded_data.len() < 3 {
         return Err("Encrypted data too short".into());
     }
-    
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-    let nonce = Nonce::from_slice(&decoded_data[..12]);
-    let ciphertext = &decoded_data[12..];
-    
-    let plaintext = cipher.decrypt(nonce, ciphertext)
-        .map_err(|e| format!("Decryption failed: {}", e))?;
-    
-    String::from_utf8(plaintext)
-        .map_err(|e| format!("Invalid UTF-8: {}", e).into())
+    let version = decoded_data[0];
+    if version != ENVELOPE_VERSION {
+        return Err(format!("Unsupported envelope version {}", version).into());
+    }
+    let cipher_id = CipherId::try_from(decoded_data[1])?;
+    let kdf_id = KdfId::try_from(decoded_data[2])?;
+    let aad = envelope_aad(version, cipher_id, kdf_id, aad);
+    open_with(cipher_id.cipher(), &decoded_data[3..], key, &aad)
+}
+
+// Decrypts [nonce + ciphertext] with `cipher`
+fn open_with(cipher: &dyn Cipher, data: &[u8], key: &SecretKey, aad: &[u8])
+   -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    if data.len() < cipher.nonce_len() {
+        return Err("Encrypted data too short".into());
+    }
+    let (nonce, ciphertext) = data.split_at(cipher.nonce_len());
+    Ok(Zeroizing::new(cipher.decrypt(key.expose(), nonce, ciphertext, aad)?))
+}
+
+pub const SEALED_VAULT_FORMAT: &str = "pwmgr-sealed-vault";
+pub const SEALED_VAULT_VERSION: u32 = 1;
+pub const VAULT_DOCUMENT_FORMAT: &str = "pwmgr-vault";
+pub const VAULT_DOCUMENT_VERSION: u32 = 1;
+
+//Credential DB file contents, as read from disk
+pub enum DbFile {
+    //The vault document, upgraded from an older layout by the migrations 
+    //listed (if any)
+    Document(VaultDocument, Vec<&'static Migration>),
+    Sealed(SealedVault),
+}
+
+/// Parses the contents of the credential DB file, plain or sealed. A plain
+/// DB file in an older layout is migrated to the current one.
+pub fn parse_db_file(contents: &str) -> Result<DbFile, Box<dyn Error>> {
+    let value: serde_json::Value = serde_json::from_str(contents)?;
+    match value.get("format").and_then(|f| f.as_str()) {
+        Some(SEALED_VAULT_FORMAT) => 
+            Ok(DbFile::Sealed(serde_json::from_value(value)?)),
+        _ => {
+            let (document, migrations) = migrate::upgrade(value)?;
+            Ok(DbFile::Document(serde_json::from_value(document)?, 
+                                migrations))
+        },
+    }
+}
+
+// Associated data of a sealed vault: its plaintext header, so that the 
+// format version and KDF parameters (if any) cannot be altered undetected
+fn sealed_vault_aad(version: u32, kdf_params: Option<&KdfParams>) 
+   -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut aad = Vec::new();
+    aad.extend_from_slice(SEALED_VAULT_FORMAT.as_bytes());
+    aad.extend_from_slice(&version.to_be_bytes());
+    if let Some(kdf_params) = kdf_params {
+        aad.extend_from_slice(&serde_json::to_vec(kdf_params)?);
+    }
+    Ok(aad)
+}
+
+/// Seals the whole credential DB with the vault key
+pub fn seal_db(db: &CredentialStore, vault_key: &SecretKey, 
+               cipher_id: CipherId) 
+       -> Result<SealedVault, Box<dyn Error>> {
+    let aad = sealed_vault_aad(SEALED_VAULT_VERSION, None)?;
+    Ok(SealedVault {
+        format: SEALED_VAULT_FORMAT.to_string(),
+        version: SEALED_VAULT_VERSION,
+        kdf: None,
+        payload: encrypt_bytes(&Zeroizing::new(serde_json::to_vec(db)?), vault_key, 
+                               cipher_id, KdfId::VaultKey, &aad)?,
+        integrity: None,
+    })
+}
+
+/// Opens a sealed credential DB with the vault key
+pub fn unseal_db(sealed: &SealedVault, vault_key: &SecretKey) 
+       -> Result<CredentialStore, Box<dyn Error>> {
+    if sealed.version != SEALED_VAULT_VERSION {
+        return Err(format!("Unsupported sealed vault version {}", 
+                           sealed.version).into());
+    }
+    let aad = sealed_vault_aad(sealed.version, sealed.kdf.as_ref())?;
+    let payload = decrypt_bytes(&sealed.payload, vault_key, &aad)
+        .map_err(|_| "Sealed vault does not authenticate (tampered or corrupted)")?;
+    Ok(serde_json::from_slice(&payload)?)
 }
 
 //Function to display cleartext password on the screen:
@@ -132,13 +1049,13 @@ pub fn decrypt(
 //    Clear it from the screen
 //    Uses "crossterm" crate
 
-pub fn print_password_cleartext(passwd: &str, duration: Duration)
+pub fn print_password_cleartext(passwd: &SecretString, duration: Duration)
        -> Result<Duration, Box<dyn Error>> {
 
     //Switch to raw mode
     terminal::enable_raw_mode()?;
     execute!(stdout(), cursor::SavePosition)?;
-    print!("{:?}", passwd);
+    print!("{:?}", passwd.expose());
     stdout().flush()?;
 
     //Poll for key strokes OR until duration has elapsed
@@ -172,54 +1089,154 @@ pub fn print_password_cleartext(passwd: &str, duration: Duration)
 //
 // * `raw_file_name` - 
 // Path to the file containing raw credentials, with each line formatted as:
-// <site> <user> <username> <password>.
+// <site> <user> <username> <password> (see import.rs), or to an export of 
+// another password manager (see importers.rs).
+// * `format` - Format of the file, detected if ImportFormat::Auto.
 //
 // # Returns
 //
-// * `Ok(CredentialStore)` - A populated CredentialStore HashMap on success.
-// * `Err(Box<dyn Error>)` - An error if the file cannot be read or parsed.
+// * `Ok((CredentialStore, ImportSummary))` - A populated CredentialStore 
+//   HashMap, and the number of credentials imported and lines skipped.
+// * `Err(Box<dyn Error>)` - An error if the file cannot be read, or a 
+//   credential cannot be encrypted.
 //
-pub fn populate_db(raw_file_name: String, master_key: &[u8; 32]) -> 
-                   Result<CredentialStore, Box<dyn Error>> { 
-    // Read the file content 
-    // TODO: Modify to use BufReader, 
-    // in order to avoid reading the entire content
-    let file_content = match fs::read_to_string(raw_file_name) {
-        Ok(contents) => contents,
-        Err(error)   => return Err(Box::new(error)),
-    };
-
+pub fn populate_db(raw_file_name: &str, format: ImportFormat, 
+                   master_key: &SecretKey, cipher_id: CipherId) -> 
+                   Result<(CredentialStore, ImportSummary), Box<dyn Error>> { 
     let mut db: HashMap<String, SiteUser> = HashMap::new();
 
-    // 
-    // Read file_content, one line at a time: 
-    // <site> <user> <username> <password>
-    for line in file_content.lines() {
-        let mut tokens = line.split_whitespace();
-        let site = tokens.next().unwrap().to_string();
-
-        // Check if the site is already present in the HashMap
-        if let Some(site_user_map) = db.get_mut(&site) { 
-            site_user_map.insert(tokens.next().unwrap().to_string(), 
-                Credential {
-                    username: tokens.next().unwrap().to_string(), 
-                    password: encrypt(tokens.next().unwrap(), master_key)
-                             .map_err(|e| format!("Encryption failed {}", e))?
-                }
-            );
-        } 
-        else {
-            let mut site_user_map = HashMap::new();
-            site_user_map.insert(tokens.next().unwrap().to_string(), 
-                Credential {
-                    username: tokens.next().unwrap().to_string(), 
-                    password: encrypt(tokens.next().unwrap(), master_key)
-                             .map_err(|e| format!("Encryption failed {}", e))?
-                }
-            );
-            db.insert(site, site_user_map);
-        };
-    };
+    let mut sink = ImportSink::new(|raw: import::RawCredential| {
+        let cred = encrypt_credential(&raw.site, &raw.user, raw.username, 
+                                      &raw.password, master_key, cipher_id)
+            .map_err(|e| format!("Encryption failed {}", e))?;
+        db.entry(raw.site).or_default().insert(raw.user, cred);
+        Ok(())
+    });
+    importers::read_credentials(raw_file_name, format, &mut sink)?;
+    let summary = sink.finish();
+
+    Ok((db, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_bytes(&mut [byte; 32]).unwrap()
+    }
 
-    Ok(db)
+    //A blob as written before envelopes: Base64 of the nonce and AES-256-GCM
+    //ciphertext, without associated data
+    fn legacy_blob(plaintext: &[u8], key: &SecretKey) -> String {
+        let nonce = [9u8; 12];
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.expose()));
+        let mut blob = nonce.to_vec();
+        blob.extend(cipher.encrypt(Nonce::from_slice(&nonce), plaintext)
+                    .unwrap());
+        STANDARD.encode(blob)
+    }
+
+    fn password(password: &str) -> SecretString {
+        SecretString::from(password.to_string())
+    }
+
+    //Adds a key slot of `kind` for `secret` (and `keyfile`) to `key_file`
+    fn add_slot(key_file: &mut MasterKeyFile, kind: SlotKind, secret: &str,
+                keyfile: Option<&SecretKey>) -> u32 {
+        let mut kdf_params = generate_kdf_params(8, 1, 1);
+        kdf_params.keyfile = keyfile.is_some();
+        let master_key = derive_master_key(&password(secret), &kdf_params, 
+                                           keyfile).unwrap();
+        push_key_slot(key_file, "slot", kind, &master_key, kdf_params, &key(1))
+            .unwrap()
+    }
+
+    fn key_file() -> MasterKeyFile {
+        let mut key_file = new_master_key_file(&key(2), 
+            generate_kdf_params(8, 1, 1), &key(1)).unwrap();
+        key_file.slots.clear();
+        key_file
+    }
+
+    #[test]
+    fn tries_every_slot() {
+        let mut key_file = key_file();
+        let corrupt = add_slot(&mut key_file, SlotKind::Password, "a", None);
+        let unsupported = add_slot(&mut key_file, SlotKind::Password, "b", 
+                                   None);
+        let valid = add_slot(&mut key_file, SlotKind::Password, "c", None);
+        key_file.slots[corrupt as usize].verifier = "short".to_string();
+        key_file.slots[unsupported as usize].kdf.algorithm = "md5".to_string();
+        let unlocked = verify_key_slots(&password("c"), None, 
+            SlotKind::Password, &key_file.slots).unwrap();
+        assert_eq!(unlocked.slot_id, Some(valid));
+        //A password tried on a valid slot is wrong, whatever else failed
+        assert!(matches!(verify_key_slots(&password("a"), None, 
+            SlotKind::Password, &key_file.slots), 
+            Err(AuthError::WrongPassword)));
+        //Without any valid slot, the first error is reported
+        key_file.slots.truncate(2);
+        assert!(matches!(verify_key_slots(&password("c"), None, 
+            SlotKind::Password, &key_file.slots), 
+            Err(AuthError::CorruptVerifier(_))));
+    }
+
+    #[test]
+    fn skips_keyfile_slots_without_keyfile() {
+        let mut key_file = key_file();
+        let keyfile = add_slot(&mut key_file, SlotKind::Password, "a", 
+                               Some(&key(3)));
+        assert!(matches!(verify_key_slots(&password("a"), None, 
+            SlotKind::Password, &key_file.slots), 
+            Err(AuthError::MissingKeyfile)));
+        assert_eq!(verify_key_slots(&password("a"), Some(&key(3)), 
+            SlotKind::Password, &key_file.slots).unwrap().slot_id, 
+            Some(keyfile));
+        let password_slot = add_slot(&mut key_file, SlotKind::Password, "a", 
+                                     None);
+        assert_eq!(verify_key_slots(&password("a"), None, 
+            SlotKind::Password, &key_file.slots).unwrap().slot_id, 
+            Some(password_slot));
+    }
+
+    #[test]
+    fn only_tries_slots_of_the_kind() {
+        let mut key_file = key_file();
+        let code = add_slot(&mut key_file, SlotKind::RecoveryCode, "code", 
+                            None);
+        assert!(matches!(verify_key_slots(&password("code"), None, 
+            SlotKind::Password, &key_file.slots), 
+            Err(AuthError::WrongPassword)));
+        assert_eq!(verify_key_slots(&password("code"), None, 
+            SlotKind::RecoveryCode, &key_file.slots).unwrap().slot_id, 
+            Some(code));
+    }
+
+    #[test]
+    fn decrypts_legacy_blob() {
+        let blob = legacy_blob(b"hunter2", &key(1));
+        assert!(!blob.starts_with(ENVELOPE_PREFIX));
+        assert_eq!(decrypt_bytes(&blob, &key(1), b"").unwrap().as_slice(),
+                   b"hunter2");
+    }
+
+    #[test]
+    fn legacy_blob_does_not_decrypt_with_another_key() {
+        let blob = legacy_blob(b"hunter2", &key(1));
+        assert!(decrypt_bytes(&blob, &key(2), b"").is_err());
+        assert!(decrypt_bytes(&blob[..8], &key(1), b"").is_err());
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        for cipher_id in [CipherId::Aes256Gcm, CipherId::XChaCha20Poly1305] {
+            let envelope = encrypt_bytes(b"hunter2", &key(1), cipher_id, 
+                                         KdfId::VaultKey, b"aad").unwrap();
+            assert!(envelope.starts_with(ENVELOPE_PREFIX));
+            assert_eq!(decrypt_bytes(&envelope, &key(1), b"aad").unwrap()
+                       .as_slice(), b"hunter2");
+            assert!(decrypt_bytes(&envelope, &key(1), b"other").is_err());
+        }
+    }
 }
@@ -0,0 +1,98 @@
+//Cross-process locking of the vault.
+//
+//An advisory lock on "<master key file>.lock" is held from loading the 
+//vault until it is saved (i.e. until pwmgr exits), so concurrent pwmgr runs
+//take turns instead of overwriting each other's changes. The lock file 
+//holds the pid of the process owning the lock, for error messages.
+
+use std::fmt;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use fs2::FileExt;
+
+//How often a locked vault is checked while waiting for it
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+//Why the vault could not be locked
+#[derive(Debug)]
+pub enum LockError {
+    //Another process holds the lock (its pid, if known)
+    Locked(Option<u32>),
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Locked(Some(pid)) => 
+                write!(f, "Vault is locked by pid {}", pid),
+            LockError::Locked(None) => 
+                write!(f, "Vault is locked by another process"),
+            LockError::Io(error) => 
+                write!(f, "Could not lock vault: {}", error),
+        }
+    }
+}
+
+impl Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(error: io::Error) -> LockError {
+        LockError::Io(error)
+    }
+}
+
+//An exclusive lock on the vault, released when dropped
+pub struct VaultLock {
+    file: File,
+}
+
+impl VaultLock {
+    /// Locks the vault whose master key file is `master_key_file_name`, 
+    /// waiting up to `timeout` for another process to release it
+    pub fn acquire(master_key_file_name: &str, timeout: Duration) 
+           -> Result<VaultLock, LockError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}.lock", master_key_file_name))?;
+        let started = Instant::now();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(error) if error.kind() == 
+                    fs2::lock_contended_error().kind() => {
+                    if started.elapsed() >= timeout {
+                        return Err(LockError::Locked(read_pid(&mut file)));
+                    }
+                    sleep(RETRY_INTERVAL);
+                },
+                Err(error) => return Err(error.into()),
+            }
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        Ok(VaultLock { file })
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+//Reads the pid of the lock owner from the lock file
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
@@ -1,10 +1,20 @@
 use std::process;
-use std::collections::HashMap;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::fs;
-use pwmgr::structs::Credential;
-use rpassword;
-use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::io::Write;
+use pwmgr::structs::{Credential, JournalEntry, JournalOp, KdfParams, KeySlot,
+                     MasterKeyFile, SlotKind};
+use pwmgr::secret::{SecretKey, SecretString};
+use pwmgr::throttle::{self, Throttle, UnlockPermission};
+use pwmgr::AuthError;
+use pwmgr::persist;
+use pwmgr::lock::VaultLock;
+use pwmgr::storage::{self, StorageKind, VaultStorage};
+use pwmgr::journal::{self, Journal};
+use pwmgr::integrity::{self, IntegrityCheck};
+use pwmgr::import::{self, ConflictPolicy, ImportSink, RawCredential};
+use pwmgr::importers::{self, ImportFormat};
+use std::time::Duration;
 //use log::{debug, info, warn};
 
 #[derive(Parser)]
@@ -22,19 +32,204 @@ pub struct Cli {
     #[arg(short, long, value_name="MASTER_KEY_HASH_FILE_NAME")]
     master_key_hash_file_name: String,
 
+    //Specify the keyfile, if the vault requires one to unlock
+    #[arg(short, long, value_name="KEYFILE")]
+    keyfile: Option<String>,
+
+    //Unlock with a one-time recovery code instead of the master password
+    #[arg(long)]
+    recovery_code: bool,
+
+    //Storage backend of the DB file, json or sqlite (by default sqlite 
+    //for .sqlite, .sqlite3 and .db files, json otherwise)
+    #[arg(long, value_name="BACKEND")]
+    storage: Option<StorageKind>,
+
+    //Seconds to wait for another pwmgr process to release the vault
+    #[arg(long, value_name="SECONDS", default_value_t = 10)]
+    lock_timeout: u64,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+//Cost parameters of the KDF deriving a master key from a new password
+#[derive(Args)]
+struct KdfArgs {
+    //Argon2id memory cost in KiB
+    #[arg(long, default_value_t = pwmgr::DEFAULT_KDF_MEMORY_KIB)]
+    kdf_memory_kib: u32,
+    //Argon2id number of passes
+    #[arg(long, default_value_t = pwmgr::DEFAULT_KDF_ITERATIONS)]
+    kdf_iterations: u32,
+    //Argon2id degree of parallelism
+    #[arg(long, default_value_t = pwmgr::DEFAULT_KDF_PARALLELISM)]
+    kdf_parallelism: u32,
+}
+
+//Keyfile to require along with a new password
+#[derive(Args)]
+struct KeyfileArgs {
+    //Require this keyfile (any existing file) along with the password
+    #[arg(long, value_name="KEYFILE", conflicts_with = "generate_keyfile")]
+    new_keyfile: Option<String>,
+    //Generate a random keyfile at this path and require it
+    #[arg(long, value_name="KEYFILE")]
+    generate_keyfile: Option<String>,
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    SetMasterPassword {},
+    //Change the master password of the key slot used to unlock.
+    //Without any keyfile option, the current keyfile stays required.
+    SetMasterPassword {
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        keyfile: KeyfileArgs,
+        //Stop requiring a keyfile
+        #[arg(long, conflicts_with_all = ["new_keyfile", "generate_keyfile"])]
+        no_keyfile: bool,
+    },
     Add      {site: String, user: String, username: String},
     Retrieve {site: String, user: String},
     Update   {site: String, user: String, username: String},
     Delete   {site: String, user: String},
     List     {},
     Show     {site: String},
+    //Show the journal of changes to the credentials (of a site, or of one 
+    //user on a site, along with the user's previous passwords)
+    History  {site: Option<String>, user: Option<String>},
+    //Revert the last change to the credentials
+    Undo     {},
+    //Apply the last undone change again
+    Redo     {},
+    //Re-encrypt the vault with another cipher 
+    //(aes-256-gcm or xchacha20-poly1305)
+    SetCipher {cipher: pwmgr::CipherId},
+    //Refuse to unlock after this many consecutive failed attempts 
+    //(0 disables the hard lockout)
+    SetLockout {attempts: u32},
+    //Encrypt the whole Credential DB, not just the passwords
+    Seal     {},
+    //Go back to encrypting just the passwords in the Credential DB
+    Unseal   {},
+    //Manage key slots: independent passwords that each unlock the vault
+    Slot {
+        #[command(subcommand)]
+        command: SlotCommands,
+    },
+    //Recover a vault whose master password is lost
+    Recovery {
+        #[command(subcommand)]
+        command: RecoveryCommands,
+    },
+    //Manage the backups taken before every change to the vault
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommands,
+    },
+    //Merge the credentials of a raw credentials file, or of an export of 
+    //another password manager, into the vault (unlike -r, which replaces 
+    //the vault with them)
+    Import {
+        file: String,
+        //Format of the file: auto (detected), raw, chrome, firefox, 
+        //bitwarden, keepass, lastpass or pass (a password store directory)
+        #[arg(long, value_name="FORMAT", default_value_t = ImportFormat::Auto)]
+        format: ImportFormat,
+        //What to do when a site and user are stored with another username 
+        //or password: skip, overwrite, keep-both or prompt
+        #[arg(long, value_name="POLICY", default_value_t = ConflictPolicy::Skip)]
+        on_conflict: ConflictPolicy,
+        //Show what would be added, changed or left alone, without importing
+        #[arg(long)]
+        dry_run: bool,
+    },
+    //Upgrade the vault files from an older format (which every command 
+    //does as well), or just show what would change
+    Migrate {
+        //Show the migrations without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl Commands {
+    //Whether the command changes the vault, and so is preceded by a backup
+    fn is_mutating(&self) -> bool {
+        !matches!(self, 
+            Commands::List {} | Commands::Show {..} | Commands::Retrieve {..} |
+            Commands::History {..} |
+            Commands::Slot {command: SlotCommands::List {} | SlotCommands::Test {}} |
+            Commands::Recovery {command: RecoveryCommands::Status {}} |
+            Commands::Backup {command: BackupCommands::List {} | 
+                                       BackupCommands::Prune {..}} |
+            Commands::Import {dry_run: true, ..} |
+            Commands::Migrate {dry_run: true})
+    }
+}
+
+#[derive(Subcommand)]
+enum SlotCommands {
+    //Add a key slot with its own password (and optional keyfile)
+    Add {
+        name: String,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        keyfile: KeyfileArgs,
+    },
+    //List the key slots
+    List {},
+    //Check which key slot a password unlocks, without changing anything
+    Test {},
+    //Remove a key slot, so its password no longer unlocks the vault
+    Revoke {id: u32},
+}
+
+#[derive(Subcommand)]
+enum RecoveryCommands {
+    //Split the vault key into printable shares, any THRESHOLD of which 
+    //recover the vault
+    Split {
+        #[arg(long)]
+        shares: u8,
+        #[arg(long)]
+        threshold: u8,
+    },
+    //Rebuild the vault key from recovery shares and set a new password,
+    //without unlocking the vault first
+    Restore {
+        //Key slot to set the new password on (by default a new slot is added)
+        #[arg(long)]
+        slot: Option<u32>,
+        #[command(flatten)]
+        kdf: KdfArgs,
+    },
+    //Replace the one-time recovery codes with a new set
+    Codes {
+        #[arg(long, default_value_t = 10)]
+        count: usize,
+    },
+    //Show how many recovery codes remain
+    Status {},
+}
+
+#[derive(Subcommand)]
+enum BackupCommands {
+    //List the backups, oldest first
+    List {},
+    //Replace the vault with a backup, once it is verified to decrypt
+    Restore {id: String},
+    //Remove all but the newest backups
+    Prune {
+        //Number of backups to keep (by default the configured number)
+        #[arg(long)]
+        keep: Option<u32>,
+    },
+    //Set the number of backups kept (0 disables backups)
+    Keep {generations: u32},
 }
 
 fn main() {
@@ -43,9 +238,30 @@ fn main() {
 
     let args = Cli::parse();
 
-    //Get master password
-    let input_master_password = 
-        rpassword::prompt_password("Enter Master Password:").unwrap();
+    //Hold the vault lock until exiting, so concurrent runs take turns
+    let _vault_lock = match VaultLock::acquire(
+        &args.master_key_hash_file_name, 
+        Duration::from_secs(args.lock_timeout)) {
+        Ok(vault_lock) => vault_lock,
+        Err(error) => {
+            println!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    let mutating = args.command.is_mutating() || args.raw_cred_file_name.is_some();
+
+    //Finish saving the vault first, if the last run was interrupted
+    match persist::recover(
+        &persist::transaction_file_name(&args.master_key_hash_file_name)) {
+        Ok(true) => println!("Completed an interrupted save of the vault"),
+        Ok(false) => {},
+        Err(error) => {
+            println!("Error completing an interrupted save of the vault: {}", 
+                error);
+            process::exit(1);
+        }
+    }
 
     let encoded_master_key = match fs::read_to_string(
         &args.master_key_hash_file_name) {
@@ -56,301 +272,1497 @@ fn main() {
         }
     };
 
-    let master_key_hash = match STANDARD.decode(encoded_master_key) {
-        Ok(master_key_hash) => master_key_hash,
+    let mut key_file_fingerprint = 
+        persist::fingerprint(encoded_master_key.as_bytes());
+    let mut master_key_record = 
+        match pwmgr::parse_master_key_file(&encoded_master_key) {
+        Ok(master_key_record) => master_key_record,
         Err(err) => {
             println!("Invalid master key hash {}", err);
             process::exit(1);
         }
     };
-    let master_key = match pwmgr::verify_master_password(
-        &input_master_password, &master_key_hash) {
-        Ok(key) => {
-            key
+
+    //Restoring from recovery shares replaces unlocking with the password
+    if let Commands::Recovery {
+        command: RecoveryCommands::Restore {slot, kdf}} = &args.command {
+        restore_from_shares(&args.db_file_name, 
+            &args.master_key_hash_file_name, master_key_record, *slot, kdf);
+        return;
+    }
+
+    //Refuse to even try while failed attempts are being throttled
+    let mut throttle = 
+        Throttle::load(&args.master_key_hash_file_name, &master_key_record);
+    match throttle.check(throttle::now_secs()) {
+        UnlockPermission::Allowed => {},
+        UnlockPermission::RetryAfter(delay) => {
+            println!("Too many failed unlock attempts ({}), \
+                try again in {} seconds", 
+                throttle.state.failures, delay.as_secs());
+            process::exit(1);
+        },
+        UnlockPermission::LockedOut => {
+            println!("Vault is locked out after {} failed unlock attempts. \
+                Restore it from recovery shares (recovery restore).", 
+                throttle.state.failures);
+            process::exit(1);
+        },
+    }
+
+    //Read the keyfile, if any password key slot uses one
+    let recovery_code_used = args.recovery_code;
+    let (uses_keyfile, requires_keyfile) = match &master_key_record {
+        _ if recovery_code_used => (false, false),
+        pwmgr::MasterKeyRecord::Current(key_file) 
+            if !key_file.slots.is_empty() => {
+            let mut password_slots = key_file.slots.iter()
+                .filter(|slot| slot.kind == SlotKind::Password);
+            (password_slots.clone().any(|slot| slot.kdf.keyfile),
+             password_slots.all(|slot| slot.kdf.keyfile))
+        },
+        pwmgr::MasterKeyRecord::Current(key_file) => {
+            let keyfile = key_file.kdf.as_ref().is_some_and(|kdf| kdf.keyfile);
+            (keyfile, keyfile)
+        },
+        pwmgr::MasterKeyRecord::Legacy(_) => (false, false),
+    };
+    let keyfile = match &args.keyfile {
+        Some(keyfile_name) if uses_keyfile => 
+            Some(read_keyfile(keyfile_name)),
+        Some(keyfile_name) => {
+            println!("Warning: this vault does not use a keyfile, \
+                ignoring {}", keyfile_name);
+            None
+        },
+        None if requires_keyfile => {
+            println!("{}", AuthError::MissingKeyfile);
+            process::exit(1);
+        },
+        None => None,
+    };
+
+    //Get master password (or recovery code)
+    let (input_master_password, slot_kind) = if recovery_code_used {
+        let code = prompt_secret("Enter Recovery Code:");
+        (pwmgr::recovery::normalize_recovery_code(code.expose()), 
+         SlotKind::RecoveryCode)
+    }
+    else {
+        (prompt_secret("Enter Master Password:"), SlotKind::Password)
+    };
+
+    let (master_key, slot_id) = match pwmgr::verify_master_password(
+        &input_master_password, keyfile.as_ref(), slot_kind, 
+        &master_key_record) {
+        Ok(unlocked) => {
+            (unlocked.master_key, unlocked.slot_id)
+        },
+        Err(AuthError::WrongPassword) => {
+            match throttle.record_failure(throttle::now_secs(), 
+                                          &mut master_key_record) {
+                Ok(()) => if let pwmgr::MasterKeyRecord::Current(key_file) = 
+                    &master_key_record {
+                    write_master_key_file(&args.master_key_hash_file_name, 
+                                          key_file);
+                },
+                Err(error) => 
+                    println!("Error recording failed unlock attempt: {}", error),
+            }
+            if recovery_code_used {
+                println!("Invalid or already used recovery code");
+            }
+            else if uses_keyfile {
+                println!("Invalid Master Password or keyfile");
+            }
+            else {
+                println!("{}", AuthError::WrongPassword);
+            }
+            process::exit(1);
         },
         Err(error) => {
-                println!("{}",error.to_string());
+                println!("{}", error);
+                process::exit(1);
+        },
+    };
+    if throttle.state.failures > 0 {
+        println!("Warning: {} failed unlock attempt(s) since the last unlock", 
+            throttle.state.failures);
+        match throttle.clear(&mut master_key_record) {
+            Ok(()) => if let pwmgr::MasterKeyRecord::Current(key_file) = 
+                &master_key_record {
+                key_file_fingerprint = write_master_key_file(
+                    &args.master_key_hash_file_name, key_file);
+            },
+            Err(error) => 
+                println!("Error clearing failed unlock attempts: {}", error),
+        }
+    }
+
+    //Unwrap the vault key, which encrypts the credentials.
+    //Vaults written by older versions have no vault key: their credentials 
+    //are encrypted directly with the master key, until migrated below.
+    let wrapped_vault_key = match &master_key_record {
+        pwmgr::MasterKeyRecord::Current(key_file) 
+            if slot_id.is_some() || key_file.wrapped_key.is_some() => {
+            match pwmgr::unwrap_vault_key(key_file, slot_id, &master_key) {
+                Ok(vault_key) => Some(vault_key),
+                Err(error) => {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+            }
+        },
+        _ => None,
+    };
+
+    //A recovery code unlocks once: its key slot is removed right away, 
+    //before anything else can fail
+    if let (pwmgr::MasterKeyRecord::Current(key_file), Some(slot_id)) = 
+        (&mut master_key_record, slot_id) {
+        if recovery_code_used {
+            let used_slot = match pwmgr::revoke_key_slot(key_file, slot_id) {
+                Ok(slot) => slot,
+                Err(error) => {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+            };
+            key_file_fingerprint = write_master_key_file(
+                &args.master_key_hash_file_name, key_file);
+            println!("Recovery code used, {} remaining. If the master password \
+                is lost, set a new one with set-master-password.", 
+                recovery_code_count(key_file));
+            remove_slots_from_backups(&args.db_file_name, 
+                &args.master_key_hash_file_name, &[used_slot]);
+        }
+    }
+
+    let entry_key = wrapped_vault_key.clone()
+        .unwrap_or_else(|| master_key.clone());
+    let (entries_bound, sealed, cipher_id) = match &master_key_record {
+        pwmgr::MasterKeyRecord::Current(key_file) => 
+            (key_file.entries_bound, key_file.sealed, key_file.cipher),
+        pwmgr::MasterKeyRecord::Legacy(_) => 
+            (false, false, pwmgr::CipherId::default()),
+    };
+
+    //Open the Credential DB in args.db_file_name, 
+    //unless it is to be replaced by raw credentials (see below)
+    let storage_kind = args.storage
+        .unwrap_or_else(|| StorageKind::for_file(&args.db_file_name));
+    let mut storage = if args.raw_cred_file_name.is_some() {
+        or_exit(storage::replace(&args.db_file_name, storage_kind))
+    }
+    else {
+        let mut storage = open_storage(&args.db_file_name, storage_kind, 
+                                   wrapped_vault_key.as_ref(), sealed);
+        //A vault without its integrity MACs is only accepted as it is by an
+        //explicit migrate: the layout of the master key file is no proof of
+        //its age, it can be rewritten as easily as the MACs are stripped
+        let accept_unprotected = matches!(args.command, Commands::Migrate {..});
+        check_integrity(&mut *storage, &master_key_record, 
+                        wrapped_vault_key.as_ref(), accept_unprotected);
+        storage
+    };
+
+    if let Commands::Migrate {dry_run: true} = args.command {
+        print_migration_plan(&master_key_record, slot_id, 
+                             wrapped_vault_key.is_some(), entries_bound, 
+                             &*storage);
+        return;
+    }
+
+    //Migrate older vaults: legacy SHA-256 master keys move to Argon2id, 
+    //credentials are re-encrypted with a random vault key and bound to 
+    //their site, user and username, and the master key moves to a key slot
+    let mut key_file_changed = false;
+    let mut migrated = false;
+    //Vault files replacing the current ones, when restoring a backup
+    let mut restored_files: Option<(String, Vec<u8>, persist::Fingerprint)> = 
+        None;
+    let (vault_key, mut key_file, master_key, slot_id) = 
+        match (wrapped_vault_key, master_key_record, slot_id) {
+        (Some(vault_key), pwmgr::MasterKeyRecord::Current(key_file), 
+         Some(slot_id)) if entries_bound => 
+            (vault_key, *key_file, master_key, slot_id),
+        (wrapped_vault_key, master_key_record, slot_id) => {
+            println!("Migrating vault to the current format...");
+            let vault_key = 
+                wrapped_vault_key.unwrap_or_else(pwmgr::generate_vault_key);
+            let mut cred_db = or_exit(storage.load());
+            if let Err(error) = pwmgr::reencrypt_db(&mut cred_db, 
+                &entry_key, entries_bound, &vault_key, cipher_id) {
+                println!("{}, vault not migrated", error);
                 process::exit(1);
+            }
+            or_exit(storage.save(&cred_db));
+            let (key_file, master_key) = match master_key_record {
+                pwmgr::MasterKeyRecord::Legacy(_) => {
+                    let kdf_params = pwmgr::generate_kdf_params(
+                        pwmgr::DEFAULT_KDF_MEMORY_KIB, 
+                        pwmgr::DEFAULT_KDF_ITERATIONS, 
+                        pwmgr::DEFAULT_KDF_PARALLELISM);
+                    let new_master_key = 
+                        derive_master_key(&input_master_password, &kdf_params, 
+                                          None);
+                    (pwmgr::new_master_key_file(
+                        &new_master_key, kdf_params, &vault_key)
+                        .map(|key_file| (key_file, 0)), new_master_key)
+                },
+                pwmgr::MasterKeyRecord::Current(mut key_file) => {
+                    key_file.entries_bound = true;
+                    let slot_id = match slot_id {
+                        Some(slot_id) => pwmgr::rewrap_vault_key(
+                            &mut key_file, slot_id, &master_key, &vault_key)
+                            .map(|_| slot_id),
+                        None => pwmgr::move_master_key_to_slot(
+                            &mut key_file, &master_key, &vault_key),
+                    };
+                    (slot_id.map(|slot_id| (*key_file, slot_id)), master_key)
+                },
+            };
+            let (key_file, slot_id) = match key_file {
+                Ok(key_file) => key_file,
+                Err(error) => {
+                    println!("Error wrapping vault key: {}", error);
+                    process::exit(1);
+                }
+            };
+            key_file_changed = true;
+            migrated = true;
+            (vault_key, key_file, master_key, slot_id)
         },
     };
 
-    let mut cred_db = if let Some(raw_file_name) = args.raw_cred_file_name {
+    //A DB read sealed stays sealed until unsealed: the flag in the master 
+    //key file is not authenticated, so clearing it by hand must not have the
+    //next save write the DB unsealed
+    if storage.sealed() && !key_file.sealed {
+        println!("Warning: Credential DB is sealed, but the master key file \
+                  says it is not - keeping it sealed (run unseal to unseal \
+                  it)");
+        key_file.sealed = true;
+    }
+
+    if let Some(raw_file_name) = args.raw_cred_file_name {
         println!("Raw Credentials file name: {:?}", raw_file_name);
         //Load it in the 'cred_db' hashmap
-        let db = match pwmgr::populate_db(raw_file_name, &master_key){
-            Err(_error) => {
-                println!("Could not construct Hashmap from raw credentials!");
+        let (cred_db, summary) = match pwmgr::populate_db(
+            &raw_file_name, ImportFormat::Auto, &vault_key, key_file.cipher){
+            Err(error) => {
+                println!("Could not construct Hashmap from raw credentials: {}",
+                    error);
                 process::exit(1);
             },
-            Ok(db) => db
+            Ok(imported) => imported
         };
-        db
+        for skipped in summary.skipped.iter() {
+            println!("{}", skipped);
+        }
+        println!("Imported {} credential(s), {} entries not read", 
+            summary.imported, summary.skipped.len());
+        //A file with nothing to import is most likely not a raw 
+        //credentials file at all
+        if summary.imported == 0 && !summary.skipped.is_empty() {
+            println!("No credentials imported, Credential DB left unchanged");
+            process::exit(1);
+        }
+        or_exit(storage.save(&cred_db));
     }
-    else {
-        //Load the cred_db hashmap from args.db_file_name
-        let db = match fs::read_to_string(&args.db_file_name) {
-            Ok(db_file_content) => {
-                let db = match serde_json::from_str(&db_file_content) {
-                    Err(err) => {
-                        println!("Could not serialize Credential DB ({})", err);
-                        process::exit(1);
-                    },
-                    Ok(db) => db
-                };
-                db
-            },
-            Err(error) => {
-                println!("Credential file does not exist:{error}, creating new Hashmap");
-                let db:pwmgr::CredentialStore = HashMap::new();
-                db
-            },
-        };
-        db
-    };
+
+    //Back up the vault as it is on disk before changing it. Old backups are
+    //only removed once the command is done, as it may restore one of them.
+    //A vault in an older format is not backed up: its master key file may
+    //hold a key the migrated vault no longer uses.
+    let backed_up = (mutating || key_file_changed) && !migrated && 
+        backup_vault(&args.db_file_name, &args.master_key_hash_file_name, 
+                     &key_file);
+
+    //Changes to the credentials are recorded in the journal once saved
+    let mut journal = Journal::new(&args.db_file_name);
+    //Key slots removed, to be removed from the backups once saved
+    let mut removed_slots = Vec::new();
 
     //Implement actions on the credential DB here
     match args.command {
-        Commands::SetMasterPassword {} => {
-            let new_password = rpassword::prompt_password(
-                "Enter new Master Password:").unwrap();
-            let reenter_password = rpassword::prompt_password(
-                "Re-enter new Master Password:").unwrap();
-            if new_password != reenter_password {
-                println!("Passwords do not match, exiting {}, {} !", 
-                    new_password, reenter_password);
-                process::exit(1);
+        Commands::SetMasterPassword {kdf, keyfile: keyfile_args, no_keyfile} => {
+            let new_password = prompt_new_password("Master Password");
+            let new_keyfile = if no_keyfile {
+                println!("The key slot will no longer require a keyfile");
+                None
+            }
+            else if keyfile_args.new_keyfile.is_some() || 
+                    keyfile_args.generate_keyfile.is_some() {
+                new_keyfile(&keyfile_args)
             }
-            let new_master_key = pwmgr::derive_master_key(&new_password);
-            let encoded_master_key = STANDARD.encode(&new_master_key);            
+            else if pwmgr::find_key_slot(&key_file, slot_id)
+                    .is_ok_and(|slot| slot.kdf.keyfile) {
+                keyfile.clone()
+            }
+            else {
+                None
+            };
+            let (new_master_key, kdf_params) = 
+                new_master_key(&new_password, &kdf, new_keyfile.as_ref());
 
-            if let Err(error) = 
-                fs::write( args.master_key_hash_file_name, encoded_master_key) {
+            //Only the vault key is re-wrapped, the credentials are untouched.
+            //The slot of a used recovery code is gone, the password goes 
+            //into a new slot then.
+            let result = if recovery_code_used {
+                pwmgr::add_key_slot(&mut key_file, RECOVERED_SLOT_NAME, 
+                    &new_master_key, kdf_params, &vault_key)
+            }
+            else {
+                pwmgr::set_master_key(&mut key_file, slot_id, 
+                    &new_master_key, kdf_params, &vault_key).map(|_| slot_id)
+            };
+            match result {
+                Ok(slot_id) => println!("New password set on key slot {}", 
+                    slot_id),
+                Err(error) => {
+                    println!("Error wrapping vault key: {}", error);
+                    process::exit(1);
+                }
+            }
+            key_file_changed = true;
+        }
 
-                println!("Error writing master key to file: {}", error);
+        Commands::Slot {command: SlotCommands::Add {name, kdf, keyfile}} => {
+            let new_password = prompt_new_password(
+                &format!("password for key slot {:?}", name));
+            let new_keyfile = new_keyfile(&keyfile);
+            let (new_master_key, kdf_params) = 
+                new_master_key(&new_password, &kdf, new_keyfile.as_ref());
+            match pwmgr::add_key_slot(&mut key_file, &name, &new_master_key, 
+                                      kdf_params, &vault_key) {
+                Ok(id) => println!("Added key slot {} ({:?})", id, name),
+                Err(error) => {
+                    println!("Error wrapping vault key: {}", error);
+                    process::exit(1);
+                }
+            }
+            key_file_changed = true;
+        }
+
+        Commands::Slot {command: SlotCommands::List {}} => {
+            for slot in key_file.slots.iter() {
+                println!("Slot {}: {:?}{}{}", slot.id, slot.name,
+                    if slot.kdf.keyfile {" (keyfile)"} else {""},
+                    if slot.id == slot_id {" (unlocked this session)"} else {""});
+                println!("\t{} m={} KiB t={} p={}", slot.kdf.algorithm, 
+                    slot.kdf.memory_kib, slot.kdf.iterations, 
+                    slot.kdf.parallelism);
+            }
+        }
+
+        Commands::Slot {command: SlotCommands::Test {}} => {
+            let test_password = prompt_secret("Enter password to test:");
+            match pwmgr::verify_key_slots(
+                &test_password, keyfile.as_ref(), SlotKind::Password, 
+                &key_file.slots) {
+                Ok(pwmgr::Unlocked {slot_id: Some(id), ..}) => {
+                    let name = pwmgr::find_key_slot(&key_file, id)
+                        .map(|slot| slot.name.as_str()).unwrap_or_default();
+                    println!("Password unlocks key slot {} ({:?})", id, name);
+                },
+                Ok(_) => println!("Password unlocks the vault"),
+                Err(error) => println!("{}", error),
+            }
+        }
+
+        Commands::Slot {command: SlotCommands::Revoke {id}} => {
+            match pwmgr::revoke_key_slot(&mut key_file, id) {
+                Ok(slot) => {
+                    println!("Revoked key slot {} ({:?})", slot.id, slot.name);
+                    removed_slots.push(slot);
+                },
+                Err(error) => {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+            }
+            if id == slot_id {
+                println!("Warning: revoked the key slot used to unlock, \
+                    its password no longer unlocks the vault");
+            }
+            key_file_changed = true;
+        }
+
+        Commands::Recovery {command: RecoveryCommands::Split {shares, threshold}} => {
+            let shares = match pwmgr::recovery::split_vault_key(
+                &vault_key, shares, threshold) {
+                Ok(shares) => shares,
+                Err(error) => {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+            };
+            if key_file.vault_key_check.is_none() {
+                pwmgr::set_vault_key_check(&mut key_file, &vault_key);
+                key_file_changed = true;
+            }
+            if !key_file.recovery_kit {
+                key_file.recovery_kit = true;
+                key_file_changed = true;
+            }
+            println!("Recovery kit: any {} of these {} shares recover the \
+                vault without a password.", threshold, shares.len());
+            println!("Give each share to a different person or place, \
+                and keep them offline.\n");
+            for (index, share) in shares.iter().enumerate() {
+                println!("Share {} of {}:\n    {}\n", 
+                    index + 1, shares.len(), share);
+            }
+        }
+
+        Commands::Recovery {command: RecoveryCommands::Codes {count}} => {
+            if count == 0 {
+                println!("At least one recovery code must be generated");
                 process::exit(1);
             }
+            let (old_codes, slots) = key_file.slots.drain(..)
+                .partition(|slot| slot.kind == SlotKind::RecoveryCode);
+            key_file.slots = slots;
+            removed_slots = old_codes;
+            let codes = pwmgr::recovery::generate_recovery_codes(count);
+            for code in codes.iter() {
+                let code_key = 
+                    pwmgr::recovery::normalize_recovery_code(code.expose());
+                if let Err(error) = pwmgr::add_recovery_code_slot(
+                    &mut key_file, &code_key, &vault_key) {
+                    println!("Error wrapping vault key: {}", error);
+                    process::exit(1);
+                }
+            }
+            key_file_changed = true;
+            println!("Recovery codes (any previous codes no longer work).");
+            println!("Each code unlocks the vault once with --recovery-code. \
+                Keep them offline.\n");
+            for code in codes.iter() {
+                println!("    {}", code.expose());
+            }
+        }
 
-            //Re-encrypt cred_db with the new master key
-            for (_site, site_users) in cred_db.iter_mut() {
-                for (_user, cred) in site_users.iter_mut() {
-                    //Decrypt the existing password
-                    let decrypted_pass = 
-                        match pwmgr::decrypt(&cred.password, &master_key){
-
-                        Ok(decrypted_pass) => decrypted_pass,
-                        Err(error) => {
-                            let err_msg = format!(
-                                "Error decrypting password {} skipping user: {}, site: {}", 
-                                error, _user, _site);
-                            println!("{}", err_msg);
-                            continue;
-                        }
-                    };
+        Commands::Recovery {command: RecoveryCommands::Status {}} => {
+            println!("{} unused recovery code(s)", 
+                recovery_code_count(&key_file));
+        }
 
-                    //Re-encrypt with new master password
-                    let new_encrypted_pass = 
-                        match pwmgr::encrypt(&decrypted_pass, &new_master_key) {
-
-                        Ok(encrypted_pass) => encrypted_pass,
-                        Err(error) => {
-                            let err_msg = format!(
-                                "Error re-encrypting password {} skipping user: {}, site: {}", 
-                                error, _user, _site);
-                            println!("{}", err_msg);
-                            continue;
-                        }
-                    };
-                    cred.password = new_encrypted_pass;
+        Commands::Recovery {command: RecoveryCommands::Restore {..}} => {
+            unreachable!("handled before unlocking");
+        }
+
+        Commands::Backup {command: BackupCommands::List {}} => {
+            let backups = match pwmgr::backup::list(
+                &args.db_file_name, &args.master_key_hash_file_name) {
+                Ok(backups) => backups,
+                Err(error) => {
+                    println!("Error listing backups: {}", error);
+                    process::exit(1);
                 }
+            };
+            println!("{} backup(s), keeping the newest {}", backups.len(),
+                backup_generations(&key_file));
+            for backup in backups.iter() {
+                println!("\t{}{}", backup.id, 
+                    if backup.db_file().is_none() {" (no Credential DB)"} 
+                    else {""});
             }
         }
 
-        Commands::List {} => {
-            for (site, site_users) in cred_db.iter() {
-                println!("Site: {:?}", site);
-                for (user, cred) in site_users.iter() {
-                    let formatted_cred = 
-                        format!("\n\t  username: {} \n\t  password: {}",
-                        cred.username, cred.password);
-                    println!("\tUser: {} \n\tCredentials: {}\n", 
-                        user, formatted_cred);
+        Commands::Backup {command: BackupCommands::Restore {id}} => {
+            let restored = pwmgr::backup::find(
+                    &args.db_file_name, &args.master_key_hash_file_name, &id)
+                .and_then(|backup| pwmgr::backup::verify(
+                    &backup, &vault_key, storage_kind));
+            let (backed_up_key_file, db_file_content) = match restored {
+                Ok(restored) => restored,
+                Err(error) => {
+                    println!("{}, not restoring it", error);
+                    process::exit(1);
                 }
+            };
+            let db_fingerprint = 
+                match persist::file_fingerprint(&args.db_file_name) {
+                Ok(db_fingerprint) => db_fingerprint,
+                Err(error) => {
+                    println!("Could not read Credential DB ({})", error);
+                    process::exit(1);
+                }
+            };
+            println!("Backup {} verified, restoring it (the key slots are \
+                      kept as they are)", id);
+            let restored_key_file = pwmgr::backup::restored_key_file(
+                backed_up_key_file, &mut key_file);
+            let key_file_content = 
+                serde_json::to_string_pretty(&restored_key_file)
+                .expect("Failed to serialize master key file");
+            restored_files = 
+                Some((key_file_content, db_file_content, db_fingerprint));
+        }
+
+        Commands::Backup {command: BackupCommands::Prune {keep}} => {
+            let keep = keep.unwrap_or_else(|| backup_generations(&key_file));
+            match pwmgr::backup::prune(&args.db_file_name, 
+                &args.master_key_hash_file_name, keep as usize) {
+                Ok(removed) => {
+                    for backup in removed.iter() {
+                        println!("Removed backup {}", backup.id);
+                    }
+                },
+                Err(error) => {
+                    println!("Error pruning backups: {}", error);
+                    process::exit(1);
+                }
+            }
+        }
+
+        Commands::Backup {command: BackupCommands::Keep {generations}} => {
+            if generations == 0 {
+                println!("Backups disabled");
+            }
+            else {
+                println!("Keeping the newest {} backup(s)", generations);
+            }
+            key_file.backup_generations = Some(generations);
+            key_file_changed = true;
+        }
+
+        Commands::Import {file, format, on_conflict, dry_run} => {
+            merge_import(&mut *storage, &mut journal, &file, format, 
+                         on_conflict, dry_run, (&vault_key, key_file.cipher));
+        }
+
+        Commands::Migrate {..} => {
+            //Older master key files and credentials were migrated above, an
+            //older DB layout when it was read; all are written when saved
+            for migration in storage.migrations() {
+                println!("Migrating Credential DB {}", migration);
+            }
+            println!("The vault is in the current format");
+        }
+
+        Commands::SetCipher {cipher} => {
+            //Re-encrypt all credentials and re-wrap the vault key with the 
+            //new cipher. A sealed DB is re-sealed with it when saved.
+            println!("Re-encrypting vault with {} (was {})", 
+                cipher, key_file.cipher);
+            let mut cred_db = or_exit(storage.load());
+            if let Err(error) = pwmgr::reencrypt_db(
+                &mut cred_db, &vault_key, true, &vault_key, cipher) {
+                println!("{}, cipher not changed", error);
+                process::exit(1);
+            }
+            or_exit(storage.save(&cred_db));
+            //Other key slots keep the vault key wrapped with the cipher 
+            //they were written with, until their password is changed
+            key_file.cipher = cipher;
+            if !recovery_code_used {
+                if let Err(error) = pwmgr::rewrap_vault_key(
+                    &mut key_file, slot_id, &master_key, &vault_key) {
+                    println!("Error wrapping vault key: {}", error);
+                    process::exit(1);
+                }
+            }
+            key_file_changed = true;
+        }
+
+        Commands::SetLockout {attempts} => {
+            if attempts == 0 {
+                println!("Disabling hard lockout after failed unlock attempts");
+                key_file.lockout_threshold = None;
+            }
+            //Only the recovery shares lift a lockout
+            else if !key_file.recovery_kit {
+                println!("A locked out vault can only be restored from \
+                    recovery shares: print a recovery kit with recovery \
+                    split first");
+                process::exit(1);
+            }
+            else {
+                println!("Locking out the vault after {} failed unlock attempts",
+                    attempts);
+                key_file.lockout_threshold = Some(attempts);
+            }
+            key_file_changed = true;
+        }
+
+        Commands::Seal {} => {
+            if key_file.sealed {
+                println!("Credential DB is already sealed");
+            }
+            else {
+                if let Err(error) = storage.set_sealing(
+                    Some((&vault_key, key_file.cipher))) {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+                println!("Sealing Credential DB, site names, users and \
+                    usernames will be encrypted as well");
+                key_file.sealed = true;
+                key_file_changed = true;
+            }
+        }
+
+        Commands::Unseal {} => {
+            if !key_file.sealed {
+                println!("Credential DB is not sealed");
+            }
+            else {
+                println!("Unsealing Credential DB, only passwords will be \
+                    encrypted");
+                key_file.sealed = false;
+                key_file_changed = true;
             }
         }
 
+        Commands::List {} => {
+            print_credentials(&or_exit(storage.entries(None)));
+        }
+
         Commands::Show {site} => {
-            if let Some(site_user) = cred_db.get(&site) {
-                println!("Site: {:?}", site);
-                for (user, cred) in site_user.iter() {
-                    let formatted_cred = 
-                        format!("\n\t  username: {} \n\t  password: {}",
-                        cred.username, cred.password);
-                    println!("\tUser: {} \n\tCredentials: {}\n", 
-                        user, formatted_cred);
+            print_credentials(&or_exit(storage.entries(Some(&site))));
+        }
+
+        Commands::History {site, user} => {
+            let entries = or_exit(journal.entries(&vault_key));
+            let entries = entries.iter()
+                .filter(|entry| site.as_ref().is_none_or(|site| entry.site == *site))
+                .filter(|entry| user.as_ref().is_none_or(|user| entry.user == *user));
+            for entry in entries {
+                let target = entry.target
+                    .map(|target| format!(" of #{}", target))
+                    .unwrap_or_default();
+                println!("#{} {} {}{} Site: {:?} User: {:?}", entry.seq, 
+                    throttle::format_utc_time(entry.timestamp), entry.op, 
+                    target, entry.site, entry.user);
+            }
+
+            //Previous passwords of one credential, newest first
+            if let (Some(site), Some(user)) = (&site, &user) {
+                if let Some(cred) = or_exit(storage.get(site, user)) {
+                    print_password_history(site, user, &cred, &vault_key);
+                }
+                else {
+                    println!(
+                    "No Credentials exist for Site: {:?} User: {:?}!", 
+                    site, user);
                 }
             }
         }
 
+        Commands::Undo {} => {
+            let entries = or_exit(journal.entries(&vault_key));
+            let Some(change) = journal::undoable(&entries) else {
+                println!("Nothing to undo");
+                process::exit(1);
+            };
+            apply_journal_entry(&mut *storage, &mut journal, JournalOp::Undo, 
+                                change, &vault_key);
+            println!("Undid #{} ({} of Site: {:?} User: {:?})", 
+                change.seq, change.op, change.site, change.user);
+        }
+
+        Commands::Redo {} => {
+            let entries = or_exit(journal.entries(&vault_key));
+            let Some(change) = journal::redoable(&entries) else {
+                println!("Nothing to redo");
+                process::exit(1);
+            };
+            apply_journal_entry(&mut *storage, &mut journal, JournalOp::Redo, 
+                                change, &vault_key);
+            println!("Redid #{} ({} of Site: {:?} User: {:?})", 
+                change.seq, change.op, change.site, change.user);
+        }
+
         Commands::Add {site, user, username} => {
-            let new_pass = 
-                rpassword::prompt_password("Enter Password:").unwrap();
-            let reentered_new_pass = 
-                rpassword::prompt_password( "Re-enter Password:").unwrap();
+            let new_pass = prompt_secret("Enter Password:");
+            let reentered_new_pass = prompt_secret("Re-enter Password:");
             if new_pass != reentered_new_pass {
                 println!("Passwords do not match, exiting!");
                 process::exit(1);
             }
-            let new_encrypted_pass = 
-                match pwmgr::encrypt(&new_pass, &master_key) {
+            let new_cred = match pwmgr::encrypt_credential(
+                &site, &user, username, &new_pass, &vault_key, key_file.cipher) {
 
-                Ok(encrypted_pass) => encrypted_pass,
+                Ok(cred) => cred,
                 Err(error) => {
                     let err_msg = format!("Error encrypting password {}",error);
                     println!("{}", err_msg);
                     process::exit(1);
                 }
             };
-            if let Some(site_user) = cred_db.get_mut(&site) {
-                if site_user.contains_key(&user) {
+            if or_exit(storage.get(&site, &user)).is_some() {
+                println!(
+                "Credentials exist for Site: {:?} User: {:?} - 
+                Use 'Update' instead", 
+                site, user);
+            }
+            else {
+                if or_exit(storage.entries(Some(&site))).is_empty() {
                     println!(
-                    "Credentials exist for Site: {:?} User: {:?} - 
-                    Use 'Update' instead", 
+                    "Adding new site: {:?} new user: {:?}", 
                     site, user);
                 }
                 else {
                     println!(
                     "Adding new user for Site: {:?} User: {:?}", 
                     site, user);
-
-                    site_user.insert(
-                        user, 
-                        Credential{username:username, 
-                        password:new_encrypted_pass}
-                    );
                 }
-            }
-            else {
-                println!(
-                "Adding new site: {:?} new user: {:?}", 
-                site, user);
-
-                let mut site_users = HashMap::new();
-                site_users.insert(
-                    user, 
-                    Credential{username:username, 
-                        password:new_encrypted_pass}
-                );
-                cred_db.insert(site, site_users);
+                or_exit(storage.put(&site, &user, new_cred.clone()));
+                journal.record(JournalOp::Add, &site, &user, 
+                               None, Some(new_cred), None);
             }
         }
 
         Commands::Delete {site, user} => {
-            if let Some(site_user) = cred_db.get_mut(&site) {
-                if !site_user.contains_key(&user) {
+            if or_exit(storage.entries(Some(&site))).is_empty() {
+                println!(
+                "No Credentials exist for this Site - Nothing to delete!" );
+            }
+            else {
+                if let Some(cred) = or_exit(storage.get(&site, &user)) {
                     println!(
-                    "No Credentials exist for Site: {:?} User: {:?} - 
-                    Nothing to delete!", site, user);
+                    "Removing Credentials for Site: {:?} User: {:?}", 
+                    site, user);
+                    or_exit(storage.delete(&site, &user));
+                    journal.record(JournalOp::Delete, &site, &user, 
+                                   Some(cred), None, None);
                 }
                 else {
                     println!(
-                    "Removing Credentials for Site: {:?} User: {:?}", 
-                    site, user);
-                    site_user.remove(&user);
+                    "No Credentials exist for Site: {:?} User: {:?} - 
+                    Nothing to delete!", site, user);
                 }
-                //if cred_db.get(&site).unwrap().is_empty() {
-                if site_user.is_empty() {
+                if or_exit(storage.entries(Some(&site))).is_empty() {
                     println!(
                         "No more Credentials exist for this Site - Removing site!" );
-                    cred_db.remove(&site);
                 }
             }
-            else {
-                println!(
-                "No Credentials exist for this Site - Nothing to delete!" );
-            }
         }
 
         Commands::Retrieve {site, user} => {
-            if let Some(site_user) = cred_db.get(&site) {
-                if let Some(cred) = site_user.get(&user) {
-                    match pwmgr::decrypt(&cred.password, &master_key){
-                        Ok(plaintext) => {
-                            println!( "Credentials for Site: {:?} User: {:?}", site, user);
-                            print!("                username: {:?} password: ", cred.username);
-                            let duration = std::time::Duration::from_secs(15);
-                            let _ = pwmgr::print_password_cleartext(
-                                &plaintext,duration)
-                                .map_err(|e| format!("Display failed: {}", e));
-                            println!();
-                        },
-                        Err(err_msg) => {
-                            println!("{}", err_msg);
-                            process::exit(1);
-                        }
-                    };
-                }
-                else {
-                    println!(
-                    "No Credentials exist for Site: {:?} User: {:?}!", 
-                    site, user);
-                }
+            if let Some(cred) = or_exit(storage.get(&site, &user)) {
+                match pwmgr::decrypt_credential(
+                    &site, &user, &cred, &vault_key){
+                    Ok(plaintext) => {
+                        println!( "Credentials for Site: {:?} User: {:?}", site, user);
+                        print!("                username: {:?} password: ", cred.username);
+                        let duration = std::time::Duration::from_secs(15);
+                        let _ = pwmgr::print_password_cleartext(
+                            &plaintext,duration)
+                            .map_err(|e| format!("Display failed: {}", e));
+                        println!();
+                    },
+                    Err(err_msg) => {
+                        println!("{}", err_msg);
+                        process::exit(1);
+                    }
+                };
             }
-            else {
+            else if or_exit(storage.entries(Some(&site))).is_empty() {
                 println!("No Credentials exist for this Site!" );
             }
+            else {
+                println!(
+                "No Credentials exist for Site: {:?} User: {:?}!", 
+                site, user);
+            }
         }
 
         Commands::Update {site, user, username} => {
-            if let Some(site_user) = cred_db.get_mut(&site) {
-                if let Some(_cred) = site_user.get_mut(&user) {
-                    println!(
-                    "Updating Credentials for Site: {:?} User: {:?}, ", 
-                    site, user);
+            if let Some(cred) = or_exit(storage.get(&site, &user)) {
+                println!(
+                "Updating Credentials for Site: {:?} User: {:?}, ", 
+                site, user);
 
-                    let new_pass = rpassword::prompt_password(
-                        "Enter Password:").unwrap();
-                    let reentered_new_pass = rpassword::prompt_password(
-                        "Re-enter Password:").unwrap();
-                    if new_pass != reentered_new_pass {
-                        println!("Passwords do not match, exiting!");
+                let new_pass = prompt_secret("Enter Password:");
+                let reentered_new_pass = prompt_secret("Re-enter Password:");
+                if new_pass != reentered_new_pass {
+                    println!("Passwords do not match, exiting!");
+                    process::exit(1);
+                }
+                
+                //The replaced password goes into the credential's history
+                let new_cred = pwmgr::encrypt_credential(
+                        &site, &user, username, &new_pass, 
+                        &vault_key, key_file.cipher)
+                    .and_then(|mut new_cred| {
+                        pwmgr::keep_previous_password(&site, &user, 
+                            cred.clone(), &mut new_cred, &vault_key, 
+                            key_file.cipher, throttle::now_secs())?;
+                        Ok(new_cred)
+                    });
+                let new_cred = match new_cred {
+                    Ok(cred) => cred,
+                    Err(error) => {
+                        let err_msg = 
+                            format!("Error encrypting password {}", error);
+                        println!("{}", err_msg);
                         process::exit(1);
                     }
-                    
-                    let new_encrypted_pass = 
-                            match pwmgr::encrypt(&new_pass, &master_key) {
-                        Ok(encrypted_pass) => encrypted_pass,
-                        Err(error) => {
-                            let err_msg = 
-                                format!("Error encrypting password {}", error);
-                            println!("{}", err_msg);
-                            process::exit(1);
-                        }
-                    };
-                    site_user.insert(
-                        user,
-                        Credential {
-                            username: username,
-                            password: new_encrypted_pass,
-                        },
-                    );
-                }
-                else {
-                    println!(
-                    "No Credentials exist for Site: {:?} User: {:?} -           
-                    Nothing to update!", site, user);
-                }
+                };
+                or_exit(storage.put(&site, &user, new_cred.clone()));
+                journal.record(JournalOp::Update, &site, &user, 
+                               Some(cred), Some(new_cred), None);
             }
-            else {
+            else if or_exit(storage.entries(Some(&site))).is_empty() {
                 println!("No Credentials exist for this Site - 
                 Nothing to update!");
             }
+            else {
+                println!(
+                "No Credentials exist for Site: {:?} User: {:?} -           
+                Nothing to update!", site, user);
+            }
+        }
+    }
+
+    if backed_up {
+        prune_backups(&args.db_file_name, &args.master_key_hash_file_name, 
+                      &key_file);
+    }
+
+    //Save the master key file (if changed) and the DB together, the DB 
+    //sealed as a whole if so configured. A restored backup is saved as it 
+    //was, but for the key slots.
+    let mut transaction = persist::Transaction::new(
+        &persist::transaction_file_name(&args.master_key_hash_file_name));
+    let saved = match restored_files {
+        Some(_) => Ok(()),
+        //Every save is a new generation, remembered by the master key file.
+        //Vaults without an integrity MAC get one on their first save.
+        None if mutating || key_file_changed || 
+                storage.integrity().is_none() || 
+                key_file.generation_mac.is_none() => {
+            let generation = key_file.generation
+                .max(storage.integrity().map(|integrity| integrity.generation))
+                .unwrap_or(0) + 1;
+            storage.load()
+                .and_then(|db| integrity::vault_mac(&db, generation, 
+                                                    &vault_key))
+                .and_then(|integrity| storage.set_integrity(integrity))
+                .map(|_| {
+                    integrity::set_saved_generation(&mut key_file, generation,
+                                                    &vault_key);
+                    key_file_changed = true;
+                })
+        },
+        None => Ok(()),
+    };
+    let saved = saved.and_then(|_| match restored_files {
+        Some((key_file_content, db_file_content, db_fingerprint)) => transaction
+            .stage(&args.master_key_hash_file_name, 
+                   key_file_content.as_bytes(), key_file_fingerprint)
+            .and_then(|_| transaction.stage(&args.db_file_name, 
+                                            &db_file_content, 
+                                            db_fingerprint))
+            .map_err(|error| error.into())
+            .and_then(|_| transaction.commit()),
+        None => {
+            let sealing = 
+                key_file.sealed.then_some((&vault_key, key_file.cipher));
+            let staged = storage.set_sealing(sealing).and_then(|_| {
+                if key_file_changed {
+                    let key_file_content = 
+                        serde_json::to_string_pretty(&key_file).
+                        expect("Failed to serialize master key file");
+                    transaction.stage(&args.master_key_hash_file_name, 
+                                      key_file_content.as_bytes(), 
+                                      key_file_fingerprint)?;
+                }
+                Ok(())
+            });
+            staged.and_then(|_| storage.commit(transaction))
+        },
+    });
+    if let Err(error) = saved {
+        println!("Error saving vault: {}", error);
+        process::exit(1);
+    }
+    remove_slots_from_backups(&args.db_file_name, 
+                              &args.master_key_hash_file_name, &removed_slots);
+    //Backups of the vault in its older format go once it is migrated
+    if migrated {
+        prune_backups(&args.db_file_name, &args.master_key_hash_file_name, 
+                      &key_file);
+    }
+    if let Err(error) = journal.append(&vault_key, key_file.cipher) {
+        println!("Warning: could not record the change in the journal: {}", 
+            error);
+    }
+}
+
+//What a merge import does with an imported credential
+enum MergeAction {
+    //Add it for this user (another than its own, when keeping both)
+    Add(String),
+    //Replace this stored credential with it
+    Overwrite(Credential),
+    //Nothing, as it is stored already
+    Unchanged,
+    //Nothing, as it conflicts with the stored credential
+    Skip,
+    //Ask what to do (on a dry run)
+    Ask,
+}
+
+//Merges the credentials of the raw credentials file `file_name` into the 
+//stored ones, resolving conflicts by `policy`, and prints what is added, 
+//changed and left alone. A dry run only prints it.
+fn merge_import(storage: &mut dyn VaultStorage, journal: &mut Journal, 
+                file_name: &str, format: ImportFormat, policy: ConflictPolicy, 
+                dry_run: bool, 
+                (vault_key, cipher_id): (&SecretKey, pwmgr::CipherId)) {
+    let format = match format {
+        ImportFormat::Auto => match importers::detect_format(file_name) {
+            Ok(format) => format,
+            Err(error) => {
+                println!("Could not import: {}", error);
+                process::exit(1);
+            }
+        },
+        format => format,
+    };
+    println!("Importing {} ({} format)", file_name, format);
+    if dry_run {
+        println!("Dry run, nothing is imported");
+    }
+    //Credentials added, overwritten, kept both, unchanged, skipped, to ask
+    let mut counts = [0usize; 6];
+    let mut sink = ImportSink::new(|raw: RawCredential| {
+        let action = merge_action(storage, &raw, policy, dry_run, vault_key)?;
+        let RawCredential {site, user, username, password} = raw;
+        let (count, label, note) = match &action {
+            MergeAction::Add(new_user) if *new_user == user => 
+                (0, "add", String::new()),
+            MergeAction::Add(new_user) => 
+                (2, "keep both", format!(" as User: {:?}", new_user)),
+            MergeAction::Overwrite(_) => (1, "overwrite", String::new()),
+            MergeAction::Unchanged => (3, "unchanged", String::new()),
+            MergeAction::Skip => (4, "skip", 
+                " (differs from the stored credential)".to_string()),
+            MergeAction::Ask => (5, "ask", 
+                " (differs from the stored credential)".to_string()),
+        };
+        counts[count] += 1;
+        println!("{:<9}  Site: {:?} User: {:?}{}", label, site, user, note);
+        if dry_run {
+            return Ok(());
+        }
+        match action {
+            MergeAction::Add(new_user) => {
+                let cred = pwmgr::encrypt_credential(&site, &new_user, 
+                    username, &password, vault_key, cipher_id)?;
+                storage.put(&site, &new_user, cred.clone())?;
+                journal.record(JournalOp::Add, &site, &new_user, 
+                               None, Some(cred), None);
+            },
+            MergeAction::Overwrite(stored) => {
+                //The replaced password goes into the credential's history
+                let mut cred = pwmgr::encrypt_credential(&site, &user, 
+                    username, &password, vault_key, cipher_id)?;
+                pwmgr::keep_previous_password(&site, &user, stored.clone(), 
+                    &mut cred, vault_key, cipher_id, throttle::now_secs())?;
+                storage.put(&site, &user, cred.clone())?;
+                journal.record(JournalOp::Update, &site, &user, 
+                               Some(stored), Some(cred), None);
+            },
+            _ => {},
+        }
+        Ok(())
+    });
+    if let Err(error) = importers::read_credentials(file_name, format, 
+                                                    &mut sink) {
+        println!("Could not import: {}", error);
+        process::exit(1);
+    }
+    let summary = sink.finish();
+    for skipped in summary.skipped.iter() {
+        println!("{}", skipped);
+    }
+    let [added, overwritten, kept_both, unchanged, skipped, to_ask] = counts;
+    println!("{}{} added, {} overwritten, {} kept both, {} unchanged, {} \
+              skipped{}; {} entries not read", 
+        if dry_run {"Would be: "} else {""}, 
+        added, overwritten, kept_both, unchanged, skipped, 
+        if to_ask > 0 {format!(", {} to ask about", to_ask)} else {String::new()},
+        summary.skipped.len());
+}
+
+//Decides what a merge import does with the imported credential `raw`
+fn merge_action(storage: &mut dyn VaultStorage, raw: &RawCredential, 
+                policy: ConflictPolicy, dry_run: bool, vault_key: &SecretKey) 
+   -> Result<MergeAction, Box<dyn std::error::Error>> {
+    let Some(stored) = storage.get(&raw.site, &raw.user)? else {
+        return Ok(MergeAction::Add(raw.user.clone()));
+    };
+    if is_imported(raw, &raw.user, &stored, vault_key)? {
+        return Ok(MergeAction::Unchanged);
+    }
+    let policy = match policy {
+        ConflictPolicy::Prompt if dry_run => return Ok(MergeAction::Ask),
+        ConflictPolicy::Prompt => 
+            prompt_conflict(&raw.site, &raw.user, &stored.username, 
+                            &raw.username),
+        policy => policy,
+    };
+    match policy {
+        ConflictPolicy::Overwrite => Ok(MergeAction::Overwrite(stored)),
+        ConflictPolicy::KeepBoth => {
+            //The credential may have been kept by an earlier import already
+            let mut n = 2;
+            loop {
+                let new_user = import::numbered_user(&raw.user, n);
+                match storage.get(&raw.site, &new_user)? {
+                    None => return Ok(MergeAction::Add(new_user)),
+                    Some(kept) if is_imported(raw, &new_user, &kept, 
+                                              vault_key)? => 
+                        return Ok(MergeAction::Unchanged),
+                    Some(_) => n += 1,
+                }
+            }
+        },
+        _ => Ok(MergeAction::Skip),
+    }
+}
+
+//Whether `cred`, stored for `user`, holds the username and password of the
+//imported credential `raw`
+fn is_imported(raw: &RawCredential, user: &str, cred: &Credential, 
+               vault_key: &SecretKey) 
+   -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(cred.username == raw.username && 
+       pwmgr::decrypt_credential(&raw.site, user, cred, vault_key)? == 
+       raw.password)
+}
+
+//Asks how to merge an imported credential conflicting with the stored one.
+//Skips it when there is no answer.
+fn prompt_conflict(site: &str, user: &str, stored_username: &str, 
+                   imported_username: &str) -> ConflictPolicy {
+    println!("Site: {:?} User: {:?} is stored with username {:?}, and \
+              imported with username {:?} (passwords may differ too)", 
+             site, user, stored_username, imported_username);
+    loop {
+        print!("[s]kip, [o]verwrite or [k]eep both? ");
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        match std::io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => return ConflictPolicy::Skip,
+            Ok(_) => {},
+        }
+        match answer.trim() {
+            "s" | "skip" => return ConflictPolicy::Skip,
+            "o" | "overwrite" => return ConflictPolicy::Overwrite,
+            "k" | "keep both" | "keep-both" => return ConflictPolicy::KeepBoth,
+            _ => {},
+        }
+    }
+}
+
+//Prints the migrations the vault is due for, without applying any
+fn print_migration_plan(master_key_record: &pwmgr::MasterKeyRecord, 
+                        slot_id: Option<u32>, has_vault_key: bool, 
+                        entries_bound: bool, storage: &dyn VaultStorage) {
+    let mut steps = Vec::new();
+    match master_key_record {
+        pwmgr::MasterKeyRecord::Legacy(_) => steps.push(
+            "Master key file: derive the master key with Argon2id instead \
+             of SHA-256".to_string()),
+        pwmgr::MasterKeyRecord::Current(_) if slot_id.is_none() => steps.push(
+            "Master key file: move the master key into a key slot"
+            .to_string()),
+        pwmgr::MasterKeyRecord::Current(_) => {},
+    }
+    if !has_vault_key {
+        steps.push("Credentials: re-encrypt with a random vault key instead \
+                    of the master key".to_string());
+    }
+    if !entries_bound {
+        steps.push("Credentials: bind every password to its site, user and \
+                    username".to_string());
+    }
+    for migration in storage.migrations() {
+        steps.push(format!("Credential DB {}", migration));
+    }
+    if storage.integrity().is_none() {
+        steps.push("Credential DB: add an integrity MAC over all credentials"
+                   .to_string());
+    }
+    else if matches!(master_key_record, pwmgr::MasterKeyRecord::Current(
+        key_file) if key_file.generation_mac.is_none()) {
+        steps.push("Master key file: authenticate the generation of the \
+                    Credential DB".to_string());
+    }
+    if steps.is_empty() {
+        println!("The vault is in the current format, nothing to migrate");
+        return;
+    }
+    println!("Migrating the vault would:");
+    for step in steps.iter() {
+        println!("\t{}", step);
+    }
+    println!("Nothing changed (dry run)");
+}
+
+//Prints the previous passwords of the credential of `user` on `site`, 
+//newest first, each shown like retrieve does
+fn print_password_history(site: &str, user: &str, 
+                          cred: &Credential, 
+                          vault_key: &SecretKey) {
+    println!("{} previous password(s) for Site: {:?} User: {:?}", 
+        cred.history.len(), site, user);
+    for previous in cred.history.iter().rev() {
+        match pwmgr::decrypt_previous_password(site, user, previous, vault_key) {
+            Ok(plaintext) => {
+                print!("    replaced {} username: {:?} password: ", 
+                    throttle::format_utc_time(previous.replaced), 
+                    previous.username);
+                let duration = std::time::Duration::from_secs(15);
+                let _ = pwmgr::print_password_cleartext(&plaintext, duration)
+                    .map_err(|e| format!("Display failed: {}", e));
+                println!();
+            },
+            Err(err_msg) => {
+                println!("{}", err_msg);
+                process::exit(1);
+            }
         }
     }
+}
 
-    //Save DB to file in JSON format
-    let db_file_content = 
-        serde_json::to_string_pretty(&cred_db).
-        expect("Failed to serialize DB");
+//Undoes or redoes (`op`) the journaled `change`, and records that in the
+//journal. Exits if the credential was changed since.
+fn apply_journal_entry(storage: &mut dyn VaultStorage, journal: &mut Journal, 
+                       op: JournalOp, change: &JournalEntry, 
+                       vault_key: &SecretKey) {
+    let (site, user) = (&change.site, &change.user);
+    let (expected, replacement) = match op {
+        JournalOp::Undo => (&change.current, &change.previous),
+        _ => (&change.previous, &change.current),
+    };
+    let current = or_exit(storage.get(site, user));
+    if !or_exit(journal::same_credential(
+        site, user, current.as_ref(), expected.as_ref(), vault_key)) {
+        println!("Credentials for Site: {:?} User: {:?} were changed since \
+            #{}, not doing {}", site, user, change.seq, op);
+        process::exit(1);
+    }
+    match replacement {
+        Some(cred) => or_exit(storage.put(site, user, cred.clone())),
+        None => { or_exit(storage.delete(site, user)); },
+    }
+    journal.record(op, site, user, current, replacement.clone(), 
+                   Some(change.seq));
+}
+
+//Number of backup generations kept for the vault
+fn backup_generations(key_file: &MasterKeyFile) -> u32 {
+    key_file.backup_generations
+        .unwrap_or(pwmgr::backup::DEFAULT_BACKUP_GENERATIONS)
+}
+
+//Backs up the vault files as they are on disk, unless backups are 
+//disabled. Returns whether a backup was made. Exits on failure, before 
+//anything changes.
+fn backup_vault(db_file_name: &str, master_key_hash_file_name: &str, 
+                key_file: &MasterKeyFile) -> bool {
+    if backup_generations(key_file) == 0 {
+        return false;
+    }
+    if let Err(error) = 
+        pwmgr::backup::create(db_file_name, master_key_hash_file_name) {
+        println!("Error backing up vault, nothing was changed: {}", error);
+        process::exit(1);
+    }
+    true
+}
 
-    let _ = fs::write(args.db_file_name, db_file_content);
+//Removes the oldest backups beyond the number kept
+fn prune_backups(db_file_name: &str, master_key_hash_file_name: &str, 
+                 key_file: &MasterKeyFile) {
+    if let Err(error) = pwmgr::backup::prune(db_file_name, 
+        master_key_hash_file_name, backup_generations(key_file) as usize) {
+        println!("Warning: could not remove old backups: {}", error);
+    }
+}
+
+//Removes key `slots` removed from the vault from its backups as well, so 
+//they cannot unlock an older copy of it
+fn remove_slots_from_backups(db_file_name: &str, 
+                             master_key_hash_file_name: &str, 
+                             slots: &[KeySlot]) {
+    if slots.is_empty() {
+        return;
+    }
+    match pwmgr::backup::remove_key_slots(db_file_name, 
+                                          master_key_hash_file_name, slots) {
+        Ok(0) => {},
+        Ok(changed) => println!("Removed the key slot(s) from {} backup(s)", 
+                                changed),
+        Err(error) => println!("Warning: could not remove the key slot(s) \
+            from the backups, which they still unlock: {}", error),
+    }
+}
+
+//Opens the Credential DB `db_file_name`, unsealing it with the vault key if 
+//needed. A missing file yields an empty DB. Exits on failure, or when the 
+//master key file says the DB is sealed but it is not.
+fn open_storage(db_file_name: &str, kind: StorageKind, 
+                vault_key: Option<&SecretKey>, sealed: bool) 
+   -> Box<dyn VaultStorage> {
+    if !std::path::Path::new(db_file_name).exists() {
+        println!("Credential file does not exist, creating new Hashmap");
+    }
+    match storage::open(db_file_name, kind, vault_key, sealed) {
+        Ok(storage) => storage,
+        Err(error) => {
+            println!("Could not load Credential DB ({} storage): {}", 
+                kind, error);
+            process::exit(1);
+        }
+    }
+}
+
+//Verifies the integrity MAC of the credential DB against the generation 
+//last saved in the master key file. Exits if either was tampered with or,
+//unless `accept_unprotected`, lacks its MAC; warns if the DB was rolled 
+//back.
+fn check_integrity(storage: &mut dyn VaultStorage, 
+                   master_key_record: &pwmgr::MasterKeyRecord,
+                   vault_key: Option<&SecretKey>, accept_unprotected: bool) {
+    let record = storage.integrity();
+    let (pwmgr::MasterKeyRecord::Current(key_file), Some(vault_key)) = 
+        (master_key_record, vault_key) else {
+        //Vaults without a vault key were written before the integrity MAC
+        if record.is_some() {
+            println!("Credential DB has an integrity MAC, but the vault has \
+                      no vault key to verify it");
+            process::exit(1);
+        }
+        return;
+    };
+    let checked = or_exit(storage.load()
+        .and_then(|db| integrity::check(&db, record.as_ref(), key_file, 
+                                        vault_key)));
+    match checked {
+        IntegrityCheck::Current => {},
+        IntegrityCheck::RolledBack {generation, expected} => 
+            println!("Warning: Credential DB is an older copy (generation {}, \
+                      last saved {}) - changes since may have been rolled \
+                      back", generation, expected),
+        IntegrityCheck::Unprotected if accept_unprotected => {},
+        IntegrityCheck::Unprotected => {
+            println!("Credential DB has no integrity MAC (written by an older \
+                      version of pwmgr, or stripped). Check the credentials, \
+                      then run migrate to add one.");
+            process::exit(1);
+        },
+        IntegrityCheck::Incomplete(reason) if accept_unprotected => 
+            println!("Warning: {}, accepting the Credential DB as it is", 
+                     reason),
+        IntegrityCheck::Incomplete(reason) => {
+            println!("Tamper detected: {}. Check the credentials, then run \
+                      migrate to accept the Credential DB as it is.", reason);
+            process::exit(1);
+        },
+    }
+}
+
+//Returns the result of a Credential DB operation, exits on failure
+fn or_exit<T>(result: Result<T, Box<dyn std::error::Error>>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => {
+            println!("Credential DB error: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+//Prints credentials, grouped by site
+fn print_credentials(entries: &[storage::StoredCredential]) {
+    let mut current_site = None;
+    for (site, user, cred) in entries.iter() {
+        if current_site != Some(site) {
+            println!("Site: {:?}", site);
+            current_site = Some(site);
+        }
+        let formatted_cred = 
+            format!("\n\t  username: {} \n\t  password: {}",
+            cred.username, cred.password);
+        println!("\tUser: {} \n\tCredentials: {}\n", 
+            user, formatted_cred);
+    }
+}
+
+//Prompts for a password without echoing it
+fn prompt_secret(prompt: &str) -> SecretString {
+    SecretString::from(rpassword::prompt_password(prompt).unwrap())
+}
+
+//Rebuilds the vault key from recovery shares, and sets a new password on
+//key slot `slot_id` (or a new slot). The credentials are left untouched.
+//Exits on failure.
+fn restore_from_shares(db_file_name: &str, master_key_hash_file_name: &str, 
+                       master_key_record: pwmgr::MasterKeyRecord,
+                       slot_id: Option<u32>, kdf: &KdfArgs) {
+    let mut key_file = match master_key_record {
+        pwmgr::MasterKeyRecord::Current(key_file) 
+            if key_file.vault_key_check.is_some() => *key_file,
+        _ => {
+            println!("No recovery kit was made for this vault");
+            process::exit(1);
+        }
+    };
+
+    //The first share tells how many are needed
+    let mut shares: Vec<pwmgr::recovery::RecoveryShare> = Vec::new();
+    while shares.first()
+        .is_none_or(|first| shares.len() < first.threshold as usize) {
+        let text = prompt_secret(&format!("Enter recovery share {}:", 
+            shares.len() + 1));
+        match pwmgr::recovery::decode_share(text.expose()) {
+            Ok(share) if shares.iter().any(|s| s.index() == share.index()) => 
+                println!("Share {} was already entered", share.index()),
+            Ok(share) => {
+                println!("Share {} accepted ({} needed)", 
+                    share.index(), share.threshold);
+                shares.push(share);
+            },
+            Err(error) => println!("{}, enter it again", error),
+        }
+    }
+    let vault_key = match pwmgr::recovery::combine_shares(&shares) {
+        Ok(vault_key) => vault_key,
+        Err(error) => {
+            println!("{}", error);
+            process::exit(1);
+        }
+    };
+    if pwmgr::verify_vault_key(&key_file, &vault_key).is_err() {
+        println!("Shares do not recover this vault's key (shares of another \
+            vault or of different recovery kits?)");
+        process::exit(1);
+    }
+    println!("Vault key recovered");
+
+    let new_password = prompt_new_password("Master Password");
+    let (new_master_key, kdf_params) = new_master_key(&new_password, kdf, None);
+    let result = match slot_id {
+        Some(slot_id) => pwmgr::set_master_key(&mut key_file, slot_id, 
+            &new_master_key, kdf_params, &vault_key).map(|_| slot_id),
+        None => pwmgr::add_key_slot(&mut key_file, RECOVERED_SLOT_NAME, 
+            &new_master_key, kdf_params, &vault_key),
+    };
+    match result {
+        Ok(slot_id) => println!("New password set on key slot {}", slot_id),
+        Err(error) => {
+            println!("Error wrapping vault key: {}", error);
+            process::exit(1);
+        }
+    }
+
+    if backup_vault(db_file_name, master_key_hash_file_name, &key_file) {
+        prune_backups(db_file_name, master_key_hash_file_name, &key_file);
+    }
+    //Failed attempts with the lost password no longer count
+    key_file.throttle = None;
+    write_master_key_file(master_key_hash_file_name, &key_file);
+}
+
+//Number of unused recovery codes
+fn recovery_code_count(key_file: &MasterKeyFile) -> usize {
+    key_file.slots.iter()
+        .filter(|slot| slot.kind == SlotKind::RecoveryCode)
+        .count()
+}
+
+//Name of the key slot added when restoring from recovery shares, or setting
+//a new password after unlocking with a recovery code
+const RECOVERED_SLOT_NAME: &str = "recovered";
+
+//Prompts twice for a new password, exits if the two do not match
+fn prompt_new_password(what: &str) -> SecretString {
+    let new_password = prompt_secret(&format!("Enter new {}:", what));
+    let reenter_password = prompt_secret(&format!("Re-enter new {}:", what));
+    if new_password != reenter_password {
+        println!("Passwords do not match, exiting!");
+        process::exit(1);
+    }
+    new_password
+}
+
+//Reads (or generates) the keyfile chosen with --new-keyfile or 
+//--generate-keyfile, if any. Exits on failure.
+fn new_keyfile(keyfile_args: &KeyfileArgs) -> Option<SecretKey> {
+    if let Some(keyfile_name) = &keyfile_args.generate_keyfile {
+        if let Err(error) = pwmgr::generate_keyfile(keyfile_name) {
+            println!("{}", error);
+            process::exit(1);
+        }
+        println!("Generated keyfile {}, keep a copy of it: the password \
+            does not unlock the vault without it", keyfile_name);
+        Some(read_keyfile(keyfile_name))
+    }
+    else if let Some(keyfile_name) = &keyfile_args.new_keyfile {
+        println!("Keyfile {} will be required along with the password", 
+            keyfile_name);
+        Some(read_keyfile(keyfile_name))
+    }
+    else {
+        None
+    }
+}
+
+//Derives a master key from a new password (and keyfile) with fresh KDF 
+//parameters. Exits on failure.
+fn new_master_key(password: &SecretString, kdf: &KdfArgs, 
+                  keyfile: Option<&SecretKey>) -> (SecretKey, KdfParams) {
+    let mut kdf_params = pwmgr::generate_kdf_params(
+        kdf.kdf_memory_kib, kdf.kdf_iterations, kdf.kdf_parallelism);
+    kdf_params.keyfile = keyfile.is_some();
+    (derive_master_key(password, &kdf_params, keyfile), kdf_params)
+}
+
+//Derives the master key from a password (and keyfile), exits on failure
+fn derive_master_key(password: &SecretString, kdf_params: &KdfParams,
+                     keyfile: Option<&SecretKey>) 
+   -> SecretKey {
+    match pwmgr::derive_master_key(password, kdf_params, keyfile) {
+        Ok(key) => key,
+        Err(error) => {
+            println!("Error deriving master key: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+//Reads a keyfile, exits on failure
+fn read_keyfile(keyfile_name: &str) -> SecretKey {
+    match pwmgr::read_keyfile(keyfile_name) {
+        Ok(keyfile) => keyfile,
+        Err(error) => {
+            println!("{}", error);
+            process::exit(1);
+        }
+    }
+}
+
+//Writes the master key hash file, exits on failure. Returns the fingerprint
+//of the written file.
+fn write_master_key_file(file_name: &str, key_file: &MasterKeyFile) 
+   -> persist::Fingerprint {
+    let key_file_content = 
+        serde_json::to_string_pretty(key_file).
+        expect("Failed to serialize master key file");
+
+    if let Err(error) = persist::write_atomic(file_name, 
+                                              key_file_content.as_bytes()) {
+        println!("Error writing master key to file: {}", error);
+        process::exit(1);
+    }
+    persist::fingerprint(key_file_content.as_bytes())
 }
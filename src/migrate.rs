@@ -0,0 +1,137 @@
+//Migrations of the credential DB file layout.
+//
+//The credential DB file is a versioned vault document (see VaultDocument).
+//Files in an older layout are upgraded on load, one version at a time, by
+//the migrations registered below; the upgraded document is written on the
+//next save. A change to the layout bumps VAULT_DOCUMENT_VERSION and adds a
+//migration from the previous version, which only has to deal with that
+//one change. Sealed DB files have their own, authenticated header (see
+//SealedVault) and are not migrated here.
+
+use std::error::Error;
+use std::fmt;
+use serde_json::{json, Value};
+
+use crate::{VAULT_DOCUMENT_FORMAT, VAULT_DOCUMENT_VERSION};
+
+//A step from one layout version to the next
+pub struct Migration {
+    //Version migrated from, to the next one
+    pub from: u32,
+    pub description: &'static str,
+    apply: fn(Value) -> Result<Value, Box<dyn Error>>,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "version {} -> {}: {}", self.from, self.from + 1,
+               self.description)
+    }
+}
+
+//All migrations, oldest first. Version 0 is the bare CredentialStore map
+//written before the DB file had a version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "Wrap the bare credential map in a versioned vault \
+                      document",
+        apply: wrap_credentials,
+    },
+];
+
+fn wrap_credentials(credentials: Value) -> Result<Value, Box<dyn Error>> {
+    if !credentials.is_object() {
+        return Err("Credential DB is not a map of sites".into());
+    }
+    Ok(json!({
+        "format": VAULT_DOCUMENT_FORMAT,
+        "version": 1,
+        "credentials": credentials,
+    }))
+}
+
+/// Layout version of the (unsealed) credential DB file `document`
+pub fn layout_version(document: &Value) -> Result<u32, Box<dyn Error>> {
+    match document.get("format").and_then(|format| format.as_str()) {
+        //A site could be named "format", but its users are never a string
+        None => Ok(0),
+        Some(VAULT_DOCUMENT_FORMAT) => document.get("version")
+            .and_then(|version| version.as_u64())
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| "Credential DB has no valid version".into()),
+        Some(format) =>
+            Err(format!("Unknown Credential DB format {}", format).into()),
+    }
+}
+
+/// The migrations upgrading `document` to the current layout, in order
+pub fn pending(document: &Value)
+       -> Result<Vec<&'static Migration>, Box<dyn Error>> {
+    let version = layout_version(document)?;
+    if version > VAULT_DOCUMENT_VERSION {
+        return Err(format!("Credential DB layout version {} was written by a \
+                            newer version of pwmgr (this one reads up to {})",
+                           version, VAULT_DOCUMENT_VERSION).into());
+    }
+    (version..VAULT_DOCUMENT_VERSION)
+        .map(|from| MIGRATIONS.iter().find(|migration| migration.from == from)
+             .ok_or_else(|| format!("No migration from Credential DB layout \
+                                     version {}", from).into()))
+        .collect()
+}
+
+/// Upgrades `document` to the current layout. Returns the migrations
+/// applied.
+pub fn upgrade(document: Value)
+       -> Result<(Value, Vec<&'static Migration>), Box<dyn Error>> {
+    let migrations = pending(&document)?;
+    let mut document = document;
+    for migration in migrations.iter() {
+        document = (migration.apply)(document).map_err(|error|
+            format!("Could not migrate Credential DB from layout version {} \
+                     ({})", migration.from, error))?;
+    }
+    Ok((document, migrations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_bare_credential_map() {
+        let credentials = json!({
+            "gmail.com": {
+                "me": {"username": "me@gmail.com", "password": "x"},
+            },
+        });
+        assert_eq!(layout_version(&credentials).unwrap(), 0);
+        let (document, migrations) = upgrade(credentials.clone()).unwrap();
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(migrations[0].from, 0);
+        assert_eq!(document["format"], VAULT_DOCUMENT_FORMAT);
+        assert_eq!(document["version"], VAULT_DOCUMENT_VERSION);
+        assert_eq!(document["credentials"], credentials);
+        assert!(pending(&document).unwrap().is_empty());
+    }
+
+    #[test]
+    fn empty_map_is_version_0() {
+        let (document, migrations) = upgrade(json!({})).unwrap();
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(document["credentials"], json!({}));
+    }
+
+    #[test]
+    fn rejects_newer_and_unknown_layouts() {
+        let newer = json!({
+            "format": VAULT_DOCUMENT_FORMAT,
+            "version": VAULT_DOCUMENT_VERSION + 1,
+            "credentials": {},
+        });
+        assert!(upgrade(newer).is_err());
+        assert!(upgrade(json!({"format": "other"})).is_err());
+        assert!(upgrade(json!([])).is_err());
+    }
+}
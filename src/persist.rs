@@ -0,0 +1,277 @@
+//Crash-safe persistence of the vault files.
+//
+//Every file is written to a temporary file next to it, flushed to disk and
+//then renamed over the original, so a crash or a full disk never leaves a
+//truncated file behind. The master key file and the credential DB are 
+//saved together in a transaction: once all temporary files are on disk, a
+//transaction file naming them is written, and the renames are completed
+//from it on the next run if pwmgr is interrupted before it is done.
+//
+//A transaction refuses to replace a file that changed since it was read,
+//rather than silently discarding what another program wrote to it.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+use crate::structs::{PendingRename, Transaction as TransactionFile};
+
+/// Name of the transaction file of the vault whose master key file is 
+/// `master_key_file_name`
+pub fn transaction_file_name(master_key_file_name: &str) -> String {
+    format!("{}.transaction", master_key_file_name)
+}
+
+//SHA-256 of a file's contents, None if the file does not exist
+pub type Fingerprint = Option<[u8; 32]>;
+
+/// Fingerprint of a file with `contents`
+pub fn fingerprint(contents: &[u8]) -> Fingerprint {
+    Some(Sha256::digest(contents).into())
+}
+
+/// Fingerprint of the current contents of `file_name`
+pub fn file_fingerprint(file_name: &str) -> io::Result<Fingerprint> {
+    match fs::read(file_name) {
+        Ok(contents) => Ok(fingerprint(&contents)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Fails if `file_name` no longer has the fingerprint `expected` it had 
+/// when read
+pub fn check_unmodified(file_name: &str, expected: Fingerprint) 
+       -> Result<(), Box<dyn Error>> {
+    if file_fingerprint(file_name)? != expected {
+        return Err(format!("{} was modified by another program since it was \
+            read, not overwriting it", file_name).into());
+    }
+    Ok(())
+}
+
+/// Replaces `file_name` with `contents` atomically
+pub fn write_atomic(file_name: &str, contents: &[u8]) -> io::Result<()> {
+    let temp_file_name = temp_file_name(file_name);
+    write_synced(&temp_file_name, file_name, contents)?;
+    fs::rename(&temp_file_name, file_name)?;
+    sync_parent_dir(file_name)
+}
+
+/// Name of the temporary file replacing `file_name`. Files are written next
+/// to the file they replace, as rename() cannot move files across file 
+/// systems.
+pub fn temp_file_name(file_name: &str) -> String {
+    format!("{}.tmp", file_name)
+}
+
+//Writes `contents` to `file_name` and flushes it to disk. The file gets the
+//permissions of `replaces`, if that exists.
+fn write_synced(file_name: &str, replaces: &str, contents: &[u8]) 
+   -> io::Result<()> {
+    let mut file = File::create(file_name)?;
+    if let Ok(metadata) = fs::metadata(replaces) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+//Flushes the directory entry of a renamed or removed file to disk
+fn sync_parent_dir(file_name: &str) -> io::Result<()> {
+    let parent = Path::new(file_name).parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    sync_dir(parent)
+}
+
+/// Flushes the entries of directory `dir` to disk
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+//Saves several files all together, or none of them
+pub struct Transaction {
+    file_name: String,
+    renames: Vec<PendingRename>,
+    //Fingerprint each replaced file must still have when committing
+    expected: Vec<Fingerprint>,
+}
+
+impl Transaction {
+    /// Starts a transaction recorded in `file_name` 
+    /// (see transaction_file_name)
+    pub fn new(file_name: &str) -> Transaction {
+        Transaction {
+            file_name: file_name.to_string(),
+            renames: Vec::new(),
+            expected: Vec::new(),
+        }
+    }
+
+    /// Writes `contents` to a temporary file, to replace `file_name` on 
+    /// commit if the file still has the fingerprint `expected` by then
+    pub fn stage(&mut self, file_name: &str, contents: &[u8], 
+                 expected: Fingerprint) -> io::Result<()> {
+        let temp_file_name = temp_file_name(file_name);
+        write_synced(&temp_file_name, file_name, contents)?;
+        self.renames.push(PendingRename {
+            from: temp_file_name,
+            to: file_name.to_string(),
+        });
+        self.expected.push(expected);
+        Ok(())
+    }
+
+    /// Like stage(), for a temporary file (see temp_file_name) that was 
+    /// already written and flushed to disk
+    pub fn stage_file(&mut self, file_name: &str, expected: Fingerprint) {
+        self.renames.push(PendingRename {
+            from: temp_file_name(file_name),
+            to: file_name.to_string(),
+        });
+        self.expected.push(expected);
+    }
+
+    /// Fails if any staged file was modified since it was read, removing 
+    /// the temporary files then
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        for (rename, expected) in self.renames.iter().zip(self.expected.iter()) {
+            if let Err(error) = check_unmodified(&rename.to, *expected) {
+                for rename in self.renames.iter() {
+                    let _ = fs::remove_file(&rename.from);
+                }
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces all staged files, unless any of them was modified since it 
+    /// was read. Once the transaction file is written, the transaction is
+    /// complete, even if the renames are interrupted.
+    pub fn commit(self) -> Result<(), Box<dyn Error>> {
+        self.check()?;
+        let transaction = TransactionFile { renames: self.renames };
+        write_atomic(&self.file_name, &serde_json::to_vec_pretty(&transaction)?)?;
+        complete(&self.file_name, &transaction)
+    }
+}
+
+//Renames the files of a committed transaction and removes its file. 
+//Renames already done before an interruption are skipped.
+fn complete(file_name: &str, transaction: &TransactionFile) 
+   -> Result<(), Box<dyn Error>> {
+    for rename in transaction.renames.iter() {
+        match fs::rename(&rename.from, &rename.to) {
+            Err(error) if error.kind() != ErrorKind::NotFound => 
+                return Err(format!("Could not replace {} ({})", 
+                                   rename.to, error).into()),
+            _ => sync_parent_dir(&rename.to)?,
+        }
+    }
+    fs::remove_file(file_name)?;
+    sync_parent_dir(file_name)?;
+    Ok(())
+}
+
+/// Completes a transaction interrupted before all its files were replaced.
+/// Returns whether there was one.
+pub fn recover(file_name: &str) -> Result<bool, Box<dyn Error>> {
+    let contents = match fs::read_to_string(file_name) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error.into()),
+    };
+    let transaction: TransactionFile = serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is corrupt ({})", file_name, e))?;
+    complete(file_name, &transaction)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    //A fresh directory for the files of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(
+            format!("pwmgr-persist-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn commit_replaces_all_files() {
+        let dir = test_dir("commit");
+        let (key_file, db_file) = (file(&dir, "mk"), file(&dir, "db"));
+        fs::write(&key_file, b"old key file").unwrap();
+        let mut transaction = Transaction::new(&file(&dir, "mk.transaction"));
+        transaction.stage(&key_file, b"new key file", 
+                          file_fingerprint(&key_file).unwrap()).unwrap();
+        transaction.stage(&db_file, b"new db", None).unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(fs::read(&key_file).unwrap(), b"new key file");
+        assert_eq!(fs::read(&db_file).unwrap(), b"new db");
+        assert!(!Path::new(&file(&dir, "mk.transaction")).exists());
+        assert!(!Path::new(&temp_file_name(&db_file)).exists());
+    }
+
+    #[test]
+    fn commit_refuses_modified_files() {
+        let dir = test_dir("modified");
+        let (key_file, db_file) = (file(&dir, "mk"), file(&dir, "db"));
+        fs::write(&key_file, b"read").unwrap();
+        let fingerprint = file_fingerprint(&key_file).unwrap();
+        fs::write(&key_file, b"written by another program").unwrap();
+        let mut transaction = Transaction::new(&file(&dir, "mk.transaction"));
+        transaction.stage(&db_file, b"new db", None).unwrap();
+        transaction.stage(&key_file, b"new key file", fingerprint).unwrap();
+        assert!(transaction.check().is_err());
+        assert!(transaction.commit().is_err());
+        assert_eq!(fs::read(&key_file).unwrap(), b"written by another program");
+        assert!(!Path::new(&db_file).exists());
+        assert!(!Path::new(&temp_file_name(&key_file)).exists());
+    }
+
+    #[test]
+    fn recover_completes_an_interrupted_transaction() {
+        let dir = test_dir("recover");
+        let transaction_file = file(&dir, "mk.transaction");
+        assert!(!recover(&transaction_file).unwrap());
+
+        //Interrupted after the key file was renamed, before the DB was
+        let (key_file, db_file) = (file(&dir, "mk"), file(&dir, "db"));
+        fs::write(&key_file, b"new key file").unwrap();
+        fs::write(&db_file, b"old db").unwrap();
+        fs::write(temp_file_name(&db_file), b"new db").unwrap();
+        let transaction = TransactionFile {
+            renames: [&key_file, &db_file].into_iter()
+                .map(|file_name| PendingRename {
+                    from: temp_file_name(file_name),
+                    to: file_name.clone(),
+                })
+                .collect(),
+        };
+        fs::write(&transaction_file, serde_json::to_vec(&transaction).unwrap())
+            .unwrap();
+        assert!(recover(&transaction_file).unwrap());
+        assert_eq!(fs::read(&key_file).unwrap(), b"new key file");
+        assert_eq!(fs::read(&db_file).unwrap(), b"new db");
+        assert!(!Path::new(&transaction_file).exists());
+    }
+}
@@ -0,0 +1,203 @@
+//Recovery of a vault whose master password is lost.
+//
+//Recovery kit: the vault key split into shares with Shamir's secret sharing.
+//Any `threshold` of the shares rebuild the vault key, while fewer reveal 
+//nothing about it: the polynomial coefficients are drawn uniformly from all
+//of GF(256) (the blahaj fork of sharks; sharks itself never draws 0, which
+//lets fewer shares leak information, RUSTSEC-2024-0398). Each share is 
+//printed as a base32 block carrying the threshold, the share's index and a
+//checksum, so a mistyped share is reported as such instead of silently 
+//rebuilding a wrong key.
+//
+//Recovery codes: random one-time codes, each unlocking its own key slot
+//(see add_recovery_code_slot), which is removed once the code is used.
+
+use std::error::Error;
+use data_encoding::BASE32_NOPAD;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use rand::RngCore;
+use blahaj::{Share, Sharks};
+use zeroize::Zeroizing;
+
+use crate::secret::{SecretKey, SecretString};
+
+pub const SHARE_VERSION: u8 = 1;
+//Version, threshold, index, 32 key bytes and checksum
+const SHARE_LEN: usize = 3 + 32 + CHECKSUM_LEN;
+const CHECKSUM_LEN: usize = 4;
+//Characters per dash separated group of a printed share
+const GROUP_LEN: usize = 5;
+//Random bytes of a recovery code (80 bits, 16 base32 characters)
+const RECOVERY_CODE_LEN: usize = 10;
+//Characters per dash separated group of a printed recovery code
+const RECOVERY_CODE_GROUP_LEN: usize = 4;
+
+//A decoded recovery share
+pub struct RecoveryShare {
+    //Number of shares needed to rebuild the vault key
+    pub threshold: u8,
+    share: Share,
+}
+
+impl RecoveryShare {
+    //Index of the share, from 1 to the number of shares
+    pub fn index(&self) -> u8 {
+        self.share.x.0
+    }
+}
+
+/// Splits the vault key into `shares` printable shares, any `threshold` of
+/// which rebuild it
+pub fn split_vault_key(vault_key: &SecretKey, shares: u8, threshold: u8) 
+       -> Result<Vec<String>, Box<dyn Error>> {
+    if threshold < 2 {
+        return Err("At least 2 shares must be required".into());
+    }
+    if threshold > shares {
+        return Err(format!("Cannot require {} of only {} shares", 
+                           threshold, shares).into());
+    }
+    Ok(Sharks(threshold).dealer_rng(vault_key.expose(), &mut OsRng)
+        .take(shares as usize)
+        .map(|share| encode_share(threshold, &share))
+        .collect())
+}
+
+//Encodes a share as dash separated groups of base32 characters
+fn encode_share(threshold: u8, share: &Share) -> String {
+    let mut bytes = Zeroizing::new(Vec::with_capacity(SHARE_LEN));
+    bytes.push(SHARE_VERSION);
+    bytes.push(threshold);
+    bytes.extend_from_slice(&Zeroizing::new(Vec::from(share)));
+    let checksum = Sha256::digest(bytes.as_slice());
+    bytes.extend_from_slice(&checksum[..CHECKSUM_LEN]);
+
+    group(&Zeroizing::new(BASE32_NOPAD.encode(&bytes)), GROUP_LEN)
+}
+
+//Splits `encoded` into dash separated groups of `group_len` characters
+fn group(encoded: &str, group_len: usize) -> String {
+    encoded.as_bytes().chunks(group_len)
+        .map(|group| std::str::from_utf8(group).expect("base32 is ASCII"))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+//Drops spaces and dashes and uppercases, as printed shares and codes are 
+//typed back in every which way
+fn normalize(text: &str) -> Zeroizing<String> {
+    Zeroizing::new(text.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect())
+}
+
+/// Decodes a share as printed by split_vault_key. Case, spaces and dashes
+/// do not matter.
+pub fn decode_share(text: &str) -> Result<RecoveryShare, Box<dyn Error>> {
+    let normalized = normalize(text);
+    let bytes = Zeroizing::new(BASE32_NOPAD.decode(normalized.as_bytes())
+        .map_err(|_| "Share contains invalid characters")?);
+    if bytes.len() != SHARE_LEN {
+        return Err("Share has the wrong length, check for missing \
+                    or extra characters".into());
+    }
+    let (body, checksum) = bytes.split_at(SHARE_LEN - CHECKSUM_LEN);
+    if Sha256::digest(body)[..CHECKSUM_LEN] != *checksum {
+        return Err("Share checksum does not match, check for typos".into());
+    }
+    if body[0] != SHARE_VERSION {
+        return Err(format!("Unsupported share version {}", body[0]).into());
+    }
+    let share = Share::try_from(&body[2..])?;
+    if body[1] < 2 || share.x.0 == 0 {
+        return Err("Share is malformed".into());
+    }
+    Ok(RecoveryShare { threshold: body[1], share })
+}
+
+/// Rebuilds the vault key from shares of the same recovery kit
+pub fn combine_shares(shares: &[RecoveryShare]) 
+       -> Result<SecretKey, Box<dyn Error>> {
+    let threshold = shares.first().ok_or("No shares given")?.threshold;
+    if shares.iter().any(|share| share.threshold != threshold) {
+        return Err("Shares belong to different recovery kits".into());
+    }
+    let mut vault_key = Zeroizing::new(Sharks(threshold)
+        .recover(shares.iter().map(|share| &share.share))
+        .map_err(|e| format!("Could not combine shares ({})", e))?);
+    SecretKey::from_bytes(&mut vault_key)
+        .map_err(|_| "Combined vault key has invalid length".into())
+}
+
+/// Generates `count` random recovery codes, as printed for the user
+pub fn generate_recovery_codes(count: usize) -> Vec<SecretString> {
+    (0..count).map(|_| {
+        let mut bytes = Zeroizing::new([0u8; RECOVERY_CODE_LEN]);
+        OsRng.fill_bytes(bytes.as_mut_slice());
+        let encoded = Zeroizing::new(BASE32_NOPAD.encode(bytes.as_slice()));
+        SecretString::from(group(&encoded, RECOVERY_CODE_GROUP_LEN))
+    }).collect()
+}
+
+/// Turns a recovery code as typed by the user into the secret its key slot
+/// is derived from
+pub fn normalize_recovery_code(code: &str) -> SecretString {
+    SecretString::from(normalize(code).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_bytes(&mut [byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn any_threshold_shares_rebuild_the_key() {
+        let vault_key = key(7);
+        let printed = split_vault_key(&vault_key, 5, 3).unwrap();
+        assert_eq!(printed.len(), 5);
+        for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let shares: Vec<RecoveryShare> = picked.iter()
+                .map(|&i| decode_share(&printed[i]).unwrap())
+                .collect();
+            assert_eq!(combine_shares(&shares).unwrap().expose(),
+                       vault_key.expose());
+        }
+    }
+
+    #[test]
+    fn shares_decode_however_typed() {
+        let printed = split_vault_key(&key(1), 3, 2).unwrap();
+        let retyped = printed[1].replace('-', " ").to_lowercase();
+        let share = decode_share(&retyped).unwrap();
+        assert_eq!(share.index(), decode_share(&printed[1]).unwrap().index());
+        assert_eq!(share.threshold, 2);
+    }
+
+    #[test]
+    fn too_few_shares_fail() {
+        let printed = split_vault_key(&key(2), 5, 3).unwrap();
+        let shares: Vec<RecoveryShare> = printed[..2].iter()
+            .map(|text| decode_share(text).unwrap())
+            .collect();
+        assert!(combine_shares(&shares).is_err());
+    }
+
+    #[test]
+    fn mistyped_share_fails_checksum() {
+        let mut printed = split_vault_key(&key(3), 3, 2).unwrap().remove(0);
+        let last = printed.pop().unwrap();
+        printed.push(if last == 'A' {'B'} else {'A'});
+        assert!(decode_share(&printed).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_thresholds() {
+        assert!(split_vault_key(&key(4), 3, 1).is_err());
+        assert!(split_vault_key(&key(4), 2, 3).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//Wrappers for secret-bearing values: master passwords, derived keys and
+//decrypted credentials. They are wiped from memory when dropped, never
+//print their contents with {:?}, and - with the "mlock" feature - keep their
+//pages locked in RAM so they are not swapped out.
+
+use std::fmt;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+//A 256-bit key. Boxed, so the key bytes stay at one (locked) heap address
+//however the SecretKey itself is moved around.
+pub struct SecretKey(Box<[u8; 32]>);
+
+impl SecretKey {
+    /// Creates an all-zero key, to be filled in place with expose_mut()
+    pub fn zeroed() -> SecretKey {
+        let key = Box::new([0u8; 32]);
+        lock_memory(key.as_ptr(), key.len());
+        SecretKey(key)
+    }
+
+    /// Creates a key from `bytes`, wiping `bytes`
+    pub fn from_bytes(bytes: &mut [u8]) -> Result<SecretKey, String> {
+        if bytes.len() != 32 {
+            bytes.zeroize();
+            return Err(format!("Invalid key length {}", bytes.len()));
+        }
+        let mut key = SecretKey::zeroed();
+        key.0.copy_from_slice(bytes);
+        bytes.zeroize();
+        Ok(key)
+    }
+
+    pub fn expose(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> SecretKey {
+        let mut key = SecretKey::zeroed();
+        key.0.copy_from_slice(self.expose());
+        key
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_memory(self.0.as_ptr(), self.0.len());
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+//A secret string, e.g. a master password or a decrypted credential password
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> SecretString {
+        lock_memory(secret.as_ptr(), secret.capacity());
+        SecretString(secret)
+    }
+}
+
+//Compares in constant time (for strings of equal length)
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool {
+        self.0.as_bytes().ct_eq(other.0.as_bytes()).into()
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        //Unlock before zeroize(), which also truncates the String
+        let (ptr, capacity) = (self.0.as_ptr(), self.0.capacity());
+        self.0.zeroize();
+        unlock_memory(ptr, capacity);
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+//Best effort: locking fails when RLIMIT_MEMLOCK is exhausted, and the secret
+//is still wiped on drop in that case
+#[cfg(all(unix, feature = "mlock"))]
+fn lock_memory(ptr: *const u8, len: usize) {
+    if len > 0 {
+        unsafe { libc::mlock(ptr as *const libc::c_void, len); }
+    }
+}
+
+#[cfg(all(unix, feature = "mlock"))]
+fn unlock_memory(ptr: *const u8, len: usize) {
+    if len > 0 {
+        unsafe { libc::munlock(ptr as *const libc::c_void, len); }
+    }
+}
+
+#[cfg(not(all(unix, feature = "mlock")))]
+fn lock_memory(_ptr: *const u8, _len: usize) {}
+
+#[cfg(not(all(unix, feature = "mlock")))]
+fn unlock_memory(_ptr: *const u8, _len: usize) {}
@@ -0,0 +1,769 @@
+//Storage backends of the credential DB.
+//
+//The JSON backend keeps the whole DB in one JSON file (optionally sealed as
+//a whole, see SealedVault), which is rewritten on every save. The SQLite
+//backend keeps one row per credential, so adding, updating or deleting a
+//credential writes just that row. Either way nothing is written until the
+//storage is committed, and a DB replaced as a whole is saved in the same
+//persist::Transaction as the master key file.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::migrate::Migration;
+use crate::persist::{self, Fingerprint, Transaction};
+use crate::secret::SecretKey;
+use crate::structs::{Credential, VaultDocument, VaultIntegrity};
+use crate::{parse_db_file, seal_db, unseal_db, CipherId, CredentialStore,
+            DbFile, VAULT_DOCUMENT_FORMAT, VAULT_DOCUMENT_VERSION};
+
+//Storage backend of a credential DB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind {
+    Json,
+    Sqlite,
+}
+
+impl StorageKind {
+    /// Backend of the DB file `file_name`, by its extension: SQLite for
+    /// .sqlite, .sqlite3 and .db files, JSON otherwise
+    pub fn for_file(file_name: &str) -> StorageKind {
+        match Path::new(file_name).extension().and_then(|ext| ext.to_str()) {
+            Some("sqlite" | "sqlite3" | "db") => StorageKind::Sqlite,
+            _ => StorageKind::Json,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageKind::Json => "json",
+            StorageKind::Sqlite => "sqlite",
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "json" => Ok(StorageKind::Json),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(format!(
+                "Unknown storage backend {} (expected json or sqlite)", name)),
+        }
+    }
+}
+
+//A stored credential: its site, user and the (encrypted) credential
+pub type StoredCredential = (String, String, Credential);
+
+//Where the credentials of a vault are kept. Changes are only written by
+//commit(); a storage dropped without committing leaves the DB as it was.
+pub trait VaultStorage {
+    /// Reads all credentials
+    fn load(&mut self) -> Result<CredentialStore, Box<dyn Error>>;
+
+    /// Replaces all credentials with `db`
+    fn save(&mut self, db: &CredentialStore) -> Result<(), Box<dyn Error>>;
+
+    /// Reads the credential of `user` on `site`
+    fn get(&mut self, site: &str, user: &str)
+       -> Result<Option<Credential>, Box<dyn Error>>;
+
+    /// Adds the credential of `user` on `site`, or replaces it
+    fn put(&mut self, site: &str, user: &str, cred: Credential)
+       -> Result<(), Box<dyn Error>>;
+
+    /// Removes the credential of `user` on `site`, returns whether there
+    /// was one
+    fn delete(&mut self, site: &str, user: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Lists the credentials (only those of `site`, if given), grouped by
+    /// site
+    fn entries(&mut self, site: Option<&str>)
+       -> Result<Vec<StoredCredential>, Box<dyn Error>>;
+
+    /// Sets whether the DB is sealed as a whole when written, and with which
+    /// vault key and cipher
+    fn set_sealing(&mut self, sealing: Option<(&SecretKey, CipherId)>)
+       -> Result<(), Box<dyn Error>>;
+
+    /// Whether the DB was sealed as read
+    fn sealed(&self) -> bool;
+
+    /// Integrity record of the credentials as read, if any (see
+    /// integrity.rs)
+    fn integrity(&self) -> Option<VaultIntegrity>;
+
+    /// Sets the integrity record written along with the credentials
+    fn set_integrity(&mut self, integrity: VaultIntegrity)
+       -> Result<(), Box<dyn Error>>;
+
+    /// Migrations of the DB layout or schema due on the DB as read, which
+    /// the next commit writes (see migrate.rs)
+    fn migrations(&self) -> Vec<String>;
+
+    /// Writes the changes, along with the files staged in `transaction`
+    fn commit(self: Box<Self>, transaction: Transaction)
+       -> Result<(), Box<dyn Error>>;
+}
+
+/// Opens the credential DB `file_name`. `sealed` tells whether the master
+/// key file says the DB is sealed, it is unsealed with `vault_key` then.
+/// A missing DB is empty.
+pub fn open(file_name: &str, kind: StorageKind, vault_key: Option<&SecretKey>,
+            sealed: bool) -> Result<Box<dyn VaultStorage>, Box<dyn Error>> {
+    Ok(match kind {
+        StorageKind::Json =>
+            Box::new(JsonStorage::open(file_name, vault_key, sealed)?),
+        StorageKind::Sqlite =>
+            Box::new(SqliteStorage::open(file_name, sealed)?),
+    })
+}
+
+/// Opens the credential DB `file_name` to replace all its credentials,
+/// without reading them
+pub fn replace(file_name: &str, kind: StorageKind)
+       -> Result<Box<dyn VaultStorage>, Box<dyn Error>> {
+    Ok(match kind {
+        StorageKind::Json => Box::new(JsonStorage {
+            file_name: file_name.to_string(),
+            db: CredentialStore::new(),
+            fingerprint: persist::file_fingerprint(file_name)?,
+            sealing: None,
+            read_sealed: false,
+            integrity: None,
+            migrations: Vec::new(),
+        }),
+        StorageKind::Sqlite => Box::new(SqliteStorage {
+            file_name: file_name.to_string(),
+            conn: SqliteStorage::create_replacement(file_name)?,
+            fingerprint: persist::file_fingerprint(file_name)?,
+            missing: false,
+            history_column: "history",
+            replacing: true,
+            changed: false,
+        }),
+    })
+}
+
+//The whole DB in one JSON file, held in memory and rewritten on commit
+pub struct JsonStorage {
+    file_name: String,
+    db: CredentialStore,
+    //Fingerprint of the file as read
+    fingerprint: Fingerprint,
+    sealing: Option<(SecretKey, CipherId)>,
+    //Whether the file was sealed as read
+    read_sealed: bool,
+    integrity: Option<VaultIntegrity>,
+    //Layout migrations applied to the file as read
+    migrations: Vec<&'static Migration>,
+}
+
+impl JsonStorage {
+    fn open(file_name: &str, vault_key: Option<&SecretKey>, sealed: bool)
+       -> Result<JsonStorage, Box<dyn Error>> {
+        let mut storage = JsonStorage {
+            file_name: file_name.to_string(),
+            db: CredentialStore::new(),
+            fingerprint: None,
+            sealing: None,
+            read_sealed: false,
+            integrity: None,
+            migrations: Vec::new(),
+        };
+        let contents = match fs::read_to_string(file_name) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound =>
+                return Ok(storage),
+            Err(error) => return Err(error.into()),
+        };
+        storage.fingerprint = persist::fingerprint(contents.as_bytes());
+        (storage.db, storage.integrity) = match parse_db_file(&contents)
+            .map_err(|e| format!("Could not parse Credential DB ({})", e))? {
+            DbFile::Document(..) if sealed =>
+                return Err("Credential DB is expected to be sealed, but is \
+                            not - refusing to load it".into()),
+            DbFile::Document(document, migrations) => {
+                storage.migrations = migrations;
+                (document.credentials, document.integrity)
+            },
+            DbFile::Sealed(sealed_db) => {
+                let vault_key = vault_key
+                    .ok_or("Sealed Credential DB requires a wrapped vault key")?;
+                storage.read_sealed = true;
+                (unseal_db(&sealed_db, vault_key)?, sealed_db.integrity)
+            },
+        };
+        Ok(storage)
+    }
+}
+
+impl VaultStorage for JsonStorage {
+    fn load(&mut self) -> Result<CredentialStore, Box<dyn Error>> {
+        Ok(self.db.clone())
+    }
+
+    fn save(&mut self, db: &CredentialStore) -> Result<(), Box<dyn Error>> {
+        self.db = db.clone();
+        Ok(())
+    }
+
+    fn get(&mut self, site: &str, user: &str)
+       -> Result<Option<Credential>, Box<dyn Error>> {
+        Ok(self.db.get(site).and_then(|site_users| site_users.get(user))
+           .cloned())
+    }
+
+    fn put(&mut self, site: &str, user: &str, cred: Credential)
+       -> Result<(), Box<dyn Error>> {
+        self.db.entry(site.to_string()).or_default()
+            .insert(user.to_string(), cred);
+        Ok(())
+    }
+
+    fn delete(&mut self, site: &str, user: &str) -> Result<bool, Box<dyn Error>> {
+        let Some(site_users) = self.db.get_mut(site) else {
+            return Ok(false);
+        };
+        let deleted = site_users.remove(user).is_some();
+        if site_users.is_empty() {
+            self.db.remove(site);
+        }
+        Ok(deleted)
+    }
+
+    fn entries(&mut self, site: Option<&str>)
+       -> Result<Vec<StoredCredential>, Box<dyn Error>> {
+        Ok(self.db.iter()
+            .filter(|(db_site, _)| site.is_none_or(|site| *db_site == site))
+            .flat_map(|(site, site_users)| site_users.iter()
+                .map(|(user, cred)| (site.clone(), user.clone(), cred.clone())))
+            .collect())
+    }
+
+    fn set_sealing(&mut self, sealing: Option<(&SecretKey, CipherId)>)
+       -> Result<(), Box<dyn Error>> {
+        self.sealing = sealing
+            .map(|(vault_key, cipher)| (vault_key.clone(), cipher));
+        Ok(())
+    }
+
+    fn sealed(&self) -> bool {
+        self.read_sealed
+    }
+
+    fn integrity(&self) -> Option<VaultIntegrity> {
+        self.integrity.clone()
+    }
+
+    fn set_integrity(&mut self, integrity: VaultIntegrity)
+       -> Result<(), Box<dyn Error>> {
+        self.integrity = Some(integrity);
+        Ok(())
+    }
+
+    fn migrations(&self) -> Vec<String> {
+        self.migrations.iter()
+            .map(|migration| format!("layout {}", migration))
+            .collect()
+    }
+
+    fn commit(self: Box<Self>, mut transaction: Transaction)
+       -> Result<(), Box<dyn Error>> {
+        let JsonStorage {file_name, db, fingerprint, sealing, integrity, ..} = 
+            *self;
+        let contents = match sealing {
+            Some((vault_key, cipher)) => {
+                let mut sealed_db = seal_db(&db, &vault_key, cipher)?;
+                sealed_db.integrity = integrity;
+                serde_json::to_string_pretty(&sealed_db)?
+            },
+            None => serde_json::to_string_pretty(&VaultDocument {
+                format: VAULT_DOCUMENT_FORMAT.to_string(),
+                version: VAULT_DOCUMENT_VERSION,
+                integrity,
+                credentials: db,
+            })?,
+        };
+        transaction.stage(&file_name, contents.as_bytes(), fingerprint)?;
+        transaction.commit()
+    }
+}
+
+//One row per credential in an SQLite DB. The DB file is opened read-only;
+//the first change reopens it for writing, brings its schema up to date and
+//makes the changes in an SQL transaction committed along with the pwmgr
+//transaction. Replacing all credentials writes a new DB to a temporary file
+//instead, which replaces the DB file in the pwmgr transaction.
+pub struct SqliteStorage {
+    file_name: String,
+    conn: Connection,
+    //Fingerprint of the DB file as opened
+    fingerprint: Fingerprint,
+    //Whether there is no DB in the file yet, `conn` being an empty one in
+    //memory
+    missing: bool,
+    //Column the password history is read from ("'[]'" for a DB created
+    //before the history was kept)
+    history_column: &'static str,
+    //Whether `conn` is a new DB in the temporary file, replacing the DB file
+    replacing: bool,
+    //Whether an SQL transaction with changes is open on the DB file
+    changed: bool,
+}
+
+const SQLITE_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS credentials (
+    site     TEXT NOT NULL,
+    user     TEXT NOT NULL,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    history  TEXT NOT NULL DEFAULT '[]',
+    PRIMARY KEY (site, user)
+);
+CREATE TABLE IF NOT EXISTS vault_meta (
+    name  TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+)";
+
+//Whether `table` exists and has `column`
+fn has_column(conn: &Connection, table: &str, column: &str)
+   -> Result<bool, Box<dyn Error>> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column], |row| row.get(0))?)
+}
+
+//Creates the tables, or adds the columns missing from a table created by an
+//older version
+fn create_schema(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute_batch(SQLITE_SCHEMA)?;
+    if !has_column(conn, "credentials", "history")? {
+        conn.execute_batch("ALTER TABLE credentials 
+                            ADD COLUMN history TEXT NOT NULL DEFAULT '[]'")?;
+    }
+    Ok(())
+}
+
+//Reads a credential from the username, password and history columns 
+//starting at `first`
+fn credential_from_row(row: &rusqlite::Row, first: usize) 
+   -> rusqlite::Result<Credential> {
+    let history: String = row.get(first + 2)?;
+    Ok(Credential {
+        username: row.get(first)?,
+        password: row.get(first + 1)?,
+        history: serde_json::from_str(&history).map_err(|error| 
+            rusqlite::Error::FromSqlConversionFailure(
+                first + 2, rusqlite::types::Type::Text, Box::new(error)))?,
+    })
+}
+
+impl SqliteStorage {
+    fn open(file_name: &str, sealed: bool)
+       -> Result<SqliteStorage, Box<dyn Error>> {
+        if sealed {
+            return Err("The SQLite storage backend cannot seal the \
+                        Credential DB".into());
+        }
+        let fingerprint = persist::file_fingerprint(file_name)?;
+        let mut conn = None;
+        if Path::new(file_name).exists() {
+            let file_conn = Connection::open_with_flags(
+                file_name, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            if has_column(&file_conn, "credentials", "site")? {
+                conn = Some(file_conn);
+            }
+        }
+        let missing = conn.is_none();
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::open_in_memory()?;
+                create_schema(&conn)?;
+                conn
+            },
+        };
+        let history_column = if has_column(&conn, "credentials", "history")? {
+            "history"
+        }
+        else {
+            "'[]'"
+        };
+        Ok(SqliteStorage {
+            file_name: file_name.to_string(),
+            conn,
+            fingerprint,
+            missing,
+            history_column,
+            replacing: false,
+            changed: false,
+        })
+    }
+
+    //Creates an empty DB in the temporary file replacing `file_name`
+    fn create_replacement(file_name: &str)
+       -> Result<Connection, Box<dyn Error>> {
+        let temp_file_name = persist::temp_file_name(file_name);
+        match fs::remove_file(&temp_file_name) {
+            Err(error) if error.kind() != ErrorKind::NotFound =>
+                return Err(error.into()),
+            _ => {},
+        }
+        let conn = Connection::open(&temp_file_name)?;
+        if let Ok(metadata) = fs::metadata(file_name) {
+            fs::set_permissions(&temp_file_name, metadata.permissions())?;
+        }
+        create_schema(&conn)?;
+        Ok(conn)
+    }
+
+    //Reopens the DB file for writing and opens an SQL transaction for
+    //changes to it, unless one is open already or the DB file is being
+    //replaced. A missing DB is replaced by a new one.
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.replacing || self.changed {
+            return Ok(());
+        }
+        if self.missing {
+            self.conn = SqliteStorage::create_replacement(&self.file_name)?;
+            self.replacing = true;
+            return Ok(());
+        }
+        let conn = Connection::open_with_flags(
+            &self.file_name, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        conn.execute_batch("BEGIN IMMEDIATE")?;
+        //Other writers wait for the SQL transaction from now on, so the DB
+        //file is still as read when committed if it is now
+        persist::check_unmodified(&self.file_name, self.fingerprint)?;
+        create_schema(&conn)?;
+        self.conn = conn;
+        self.history_column = "history";
+        self.changed = true;
+        Ok(())
+    }
+}
+
+impl VaultStorage for SqliteStorage {
+    fn load(&mut self) -> Result<CredentialStore, Box<dyn Error>> {
+        let mut db = CredentialStore::new();
+        for (site, user, cred) in self.entries(None)? {
+            db.entry(site).or_default().insert(user, cred);
+        }
+        Ok(db)
+    }
+
+    fn save(&mut self, db: &CredentialStore) -> Result<(), Box<dyn Error>> {
+        //Any changes to the DB file are rolled back with its connection
+        self.conn = SqliteStorage::create_replacement(&self.file_name)?;
+        self.history_column = "history";
+        self.replacing = true;
+        self.changed = false;
+        let transaction = self.conn.transaction()?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO credentials (site, user, username, password, 
+                                          history)
+                 VALUES (?1, ?2, ?3, ?4, ?5)")?;
+            for (site, site_users) in db.iter() {
+                for (user, cred) in site_users.iter() {
+                    insert.execute(params![site, user, cred.username, 
+                        cred.password, serde_json::to_string(&cred.history)?])?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get(&mut self, site: &str, user: &str)
+       -> Result<Option<Credential>, Box<dyn Error>> {
+        Ok(self.conn.query_row(
+            &format!("SELECT username, password, {} FROM credentials
+                      WHERE site = ?1 AND user = ?2", self.history_column),
+            params![site, user],
+            |row| credential_from_row(row, 0))
+            .optional()?)
+    }
+
+    fn put(&mut self, site: &str, user: &str, cred: Credential)
+       -> Result<(), Box<dyn Error>> {
+        self.begin()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO credentials (site, user, username, password,
+                                                 history)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![site, user, cred.username, cred.password, 
+                    serde_json::to_string(&cred.history)?])?;
+        Ok(())
+    }
+
+    fn delete(&mut self, site: &str, user: &str) -> Result<bool, Box<dyn Error>> {
+        self.begin()?;
+        let deleted = self.conn.execute(
+            "DELETE FROM credentials WHERE site = ?1 AND user = ?2",
+            params![site, user])?;
+        Ok(deleted > 0)
+    }
+
+    fn entries(&mut self, site: Option<&str>)
+       -> Result<Vec<StoredCredential>, Box<dyn Error>> {
+        let mut select = self.conn.prepare(&format!(
+            "SELECT site, user, username, password, {} FROM credentials
+             WHERE ?1 IS NULL OR site = ?1 ORDER BY site, user",
+            self.history_column))?;
+        let rows = select.query_map(params![site], |row| Ok((
+            row.get(0)?,
+            row.get(1)?,
+            credential_from_row(row, 2)?)))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn integrity(&self) -> Option<VaultIntegrity> {
+        //A record that cannot be read is as good as none
+        self.conn.query_row(
+            "SELECT value FROM vault_meta WHERE name = 'integrity'", [],
+            |row| row.get::<_, String>(0))
+            .ok()
+            .and_then(|integrity| serde_json::from_str(&integrity).ok())
+    }
+
+    fn set_integrity(&mut self, integrity: VaultIntegrity)
+       -> Result<(), Box<dyn Error>> {
+        self.begin()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO vault_meta (name, value) 
+             VALUES ('integrity', ?1)",
+            params![serde_json::to_string(&integrity)?])?;
+        Ok(())
+    }
+
+    fn migrations(&self) -> Vec<String> {
+        //The schema of an older DB is brought up to date with the first
+        //change to it, or on commit
+        if self.history_column == "history" {
+            Vec::new()
+        }
+        else {
+            vec!["schema: add the history column of previous passwords"
+                 .to_string()]
+        }
+    }
+
+    fn set_sealing(&mut self, sealing: Option<(&SecretKey, CipherId)>)
+       -> Result<(), Box<dyn Error>> {
+        match sealing {
+            Some(_) => Err("The SQLite storage backend cannot seal the \
+                            Credential DB".into()),
+            None => Ok(()),
+        }
+    }
+
+    fn sealed(&self) -> bool {
+        false
+    }
+
+    fn commit(mut self: Box<Self>, mut transaction: Transaction)
+       -> Result<(), Box<dyn Error>> {
+        if !self.migrations().is_empty() {
+            self.begin()?;
+        }
+        let SqliteStorage {file_name, conn, fingerprint, replacing, changed,
+                           ..} = *self;
+        if replacing {
+            conn.close().map_err(|(_, error)| error)?;
+            File::open(persist::temp_file_name(&file_name))?.sync_all()?;
+            transaction.stage_file(&file_name, fingerprint);
+            transaction.commit()
+        }
+        else {
+            //The rows go first: a DB whose generation is ahead of the master
+            //key file is still current, while the reverse is a rollback. 
+            //They are not committed unless the staged files are still as 
+            //read, so the master key file is not left behind.
+            if changed {
+                transaction.check()?;
+                conn.execute_batch("COMMIT")?;
+            }
+            transaction.commit()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const KINDS: [StorageKind; 2] = [StorageKind::Json, StorageKind::Sqlite];
+
+    //A fresh directory for the files of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(
+            format!("pwmgr-storage-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    fn cred(password: &str) -> Credential {
+        Credential {
+            username: "name".to_string(),
+            password: password.to_string(),
+            history: Vec::new(),
+        }
+    }
+
+    fn transaction(dir: &Path) -> Transaction {
+        Transaction::new(&file(dir, "mk.transaction"))
+    }
+
+    //Stores credential a/1 in a new DB of `kind` in `dir`
+    fn create(dir: &Path, kind: StorageKind) -> String {
+        let db_file = file(dir, &format!("db.{}", kind));
+        let mut storage = open(&db_file, kind, None, false).unwrap();
+        storage.put("a", "1", cred("p1")).unwrap();
+        storage.commit(transaction(dir)).unwrap();
+        db_file
+    }
+
+    #[test]
+    fn credentials_round_trip() {
+        for kind in KINDS {
+            let dir = test_dir(&format!("round-trip-{}", kind));
+            let db_file = create(&dir, kind);
+            let mut storage = open(&db_file, kind, None, false).unwrap();
+            assert_eq!(storage.get("a", "1").unwrap().unwrap().password, "p1");
+            storage.put("a", "2", cred("p2")).unwrap();
+            storage.put("b", "1", cred("p3")).unwrap();
+            assert!(storage.delete("a", "1").unwrap());
+            assert!(!storage.delete("a", "1").unwrap());
+            storage.set_integrity(VaultIntegrity {
+                generation: 2,
+                mac: "mac".to_string(),
+            }).unwrap();
+            storage.commit(transaction(&dir)).unwrap();
+
+            let mut storage = open(&db_file, kind, None, false).unwrap();
+            let entries: Vec<(String, String)> = storage.entries(None).unwrap()
+                .into_iter().map(|(site, user, _)| (site, user)).collect();
+            assert_eq!(entries.len(), 2);
+            assert!(entries.contains(&("a".to_string(), "2".to_string())));
+            assert_eq!(storage.entries(Some("b")).unwrap().len(), 1);
+            assert_eq!(storage.integrity().unwrap().generation, 2);
+            assert!(storage.migrations().is_empty());
+        }
+    }
+
+    #[test]
+    fn uncommitted_changes_are_not_written() {
+        for kind in KINDS {
+            let dir = test_dir(&format!("uncommitted-{}", kind));
+            let db_file = create(&dir, kind);
+            let before = fs::read(&db_file).unwrap();
+            let mut storage = open(&db_file, kind, None, false).unwrap();
+            storage.put("a", "2", cred("p2")).unwrap();
+            storage.save(&CredentialStore::new()).unwrap();
+            drop(storage);
+            assert_eq!(fs::read(&db_file).unwrap(), before);
+        }
+    }
+
+    #[test]
+    fn db_modified_since_read_is_not_overwritten() {
+        for kind in KINDS {
+            let dir = test_dir(&format!("modified-{}", kind));
+            let db_file = create(&dir, kind);
+            let mut storage = open(&db_file, kind, None, false).unwrap();
+            let mut other = open(&db_file, kind, None, false).unwrap();
+            other.put("c", "1", cred("p4")).unwrap();
+            other.commit(transaction(&dir)).unwrap();
+            let written = storage.put("a", "2", cred("p2"))
+                .and_then(|_| storage.commit(transaction(&dir)));
+            assert!(written.is_err());
+            let mut storage = open(&db_file, kind, None, false).unwrap();
+            assert!(storage.get("c", "1").unwrap().is_some());
+            assert!(storage.get("a", "2").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn rows_are_not_committed_when_the_key_file_changed() {
+        let dir = test_dir("key-file-changed");
+        let db_file = create(&dir, StorageKind::Sqlite);
+        let key_file = file(&dir, "mk");
+        fs::write(&key_file, b"read").unwrap();
+        let fingerprint = persist::file_fingerprint(&key_file).unwrap();
+        let mut storage = open(&db_file, StorageKind::Sqlite, None, false)
+            .unwrap();
+        storage.put("a", "2", cred("p2")).unwrap();
+        fs::write(&key_file, b"changed").unwrap();
+        let mut transaction = transaction(&dir);
+        transaction.stage(&key_file, b"new", fingerprint).unwrap();
+        assert!(storage.commit(transaction).is_err());
+        assert_eq!(fs::read(&key_file).unwrap(), b"changed");
+        let mut storage = open(&db_file, StorageKind::Sqlite, None, false)
+            .unwrap();
+        assert!(storage.get("a", "2").unwrap().is_none());
+    }
+
+    #[test]
+    fn older_sqlite_schema_is_upgraded_on_commit() {
+        let dir = test_dir("old-schema");
+        let db_file = file(&dir, "db.sqlite");
+        Connection::open(&db_file).unwrap().execute_batch(
+            "CREATE TABLE credentials (site TEXT, user TEXT, username TEXT,
+                                       password TEXT);
+             INSERT INTO credentials VALUES ('a', '1', 'name', 'p1')")
+            .unwrap();
+        let before = fs::read(&db_file).unwrap();
+        let mut storage = open(&db_file, StorageKind::Sqlite, None, false)
+            .unwrap();
+        assert_eq!(storage.migrations().len(), 1);
+        assert!(storage.get("a", "1").unwrap().unwrap().history.is_empty());
+        assert_eq!(fs::read(&db_file).unwrap(), before);
+        storage.commit(transaction(&dir)).unwrap();
+        let storage = open(&db_file, StorageKind::Sqlite, None, false).unwrap();
+        assert!(storage.migrations().is_empty());
+    }
+
+    #[test]
+    fn sealed_json_db() {
+        let dir = test_dir("sealed");
+        let db_file = create(&dir, StorageKind::Json);
+        let vault_key = SecretKey::from_bytes(&mut [1u8; 32]).unwrap();
+        assert!(open(&db_file, StorageKind::Json, Some(&vault_key), true)
+                .is_err());
+        let mut storage = open(&db_file, StorageKind::Json, None, false)
+            .unwrap();
+        assert!(!storage.sealed());
+        storage.set_sealing(Some((&vault_key, CipherId::default()))).unwrap();
+        storage.commit(transaction(&dir)).unwrap();
+        assert!(!fs::read_to_string(&db_file).unwrap().contains("\"a\""));
+
+        //The master key file may not say so, the DB is still read sealed
+        let mut storage = open(&db_file, StorageKind::Json, Some(&vault_key), 
+                               false).unwrap();
+        assert!(storage.sealed());
+        assert_eq!(storage.get("a", "1").unwrap().unwrap().password, "p1");
+        assert!(open(&db_file, StorageKind::Json, None, true).is_err());
+        assert!(open(&db_file, StorageKind::Sqlite, None, true).is_err());
+    }
+}
@@ -1,7 +1,228 @@
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::{CipherId, CredentialStore};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Credential {
     pub username: String,
     pub password: String,
+    //Passwords replaced by updates, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<PreviousPassword>,
+}
+
+//A password replaced by an update, with the username it was used with. 
+//Encrypted like the current password, and also bound to the time it was
+//replaced (see lib::previous_password_aad).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviousPassword {
+    pub username: String,
+    pub password: String,
+    //Time the password was replaced, in seconds since the UNIX epoch
+    pub replaced: u64,
+}
+
+//Parameters of the key derivation function used to turn the master password
+//into the master key. These are stored next to the vault (in the master key
+//hash file), so every vault has its own random salt and cost settings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KdfParams {
+    //KDF algorithm name, currently always "argon2id"
+    pub algorithm: String,
+    //Base64 encoded random salt
+    pub salt: String,
+    //Memory cost in KiB
+    pub memory_kib: u32,
+    //Number of passes over the memory
+    pub iterations: u32,
+    //Degree of parallelism (lanes)
+    pub parallelism: u32,
+    //Whether a keyfile is fed into the KDF along with the password, as 
+    //Argon2id secret value (omitted when false, as in older files)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keyfile: bool,
+}
+
+//What unlocks a key slot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlotKind {
+    //A master password (and optional keyfile)
+    #[default]
+    Password,
+    //A one-time recovery code. The slot is removed once it has been used.
+    RecoveryCode,
+}
+
+//A key slot: one master password (and optional keyfile) that unlocks the 
+//vault. Every slot wraps the same vault key, so slots can be added or 
+//revoked without touching the credentials.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeySlot {
+    pub id: u32,
+    //Who or what the slot is for, e.g. "alice"
+    pub name: String,
+    #[serde(default)]
+    pub kind: SlotKind,
+    pub kdf: KdfParams,
+    //Base64 encoded password verifier, derived from (but not usable as) 
+    //the slot's master key
+    pub verifier: String,
+    //The vault key, encrypted with the slot's master key
+    pub wrapped_key: String,
+}
+
+//Contents of the master key hash file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MasterKeyFile {
+    //Key slots unlocking the vault
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slots: Vec<KeySlot>,
+    //Single master key of files written before key slots existed: its KDF
+    //parameters, verifier and wrapped vault key. Moved into a key slot on 
+    //the next successful unlock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verifier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>,
+    //Whether credential ciphertexts are bound to their site, user and 
+    //username with AES-GCM associated data. Older vaults are re-encrypted 
+    //with associated data on the next successful unlock.
+    #[serde(default)]
+    pub entries_bound: bool,
+    //Whether the credential DB file is sealed as a whole (see SealedVault),
+    //hiding site names, users and usernames as well as the passwords
+    #[serde(default)]
+    pub sealed: bool,
+    //Cipher used for everything the vault encrypts
+    #[serde(default)]
+    pub cipher: CipherId,
+    //Number of consecutive failed unlock attempts after which the vault 
+    //refuses to unlock at all (no hard lockout if not set)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockout_threshold: Option<u32>,
+    //Failed unlock attempts since the last successful one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<ThrottleState>,
+    //Base64 encoded check value of the vault key, derived from (but not 
+    //usable as) the key. Verifies a vault key rebuilt from recovery shares.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault_key_check: Option<String>,
+    //Whether a recovery kit of the vault key has been printed (see 
+    //recovery.rs), the only way to lift a hard lockout
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub recovery_kit: bool,
+    //Number of backup generations kept (see backup.rs), 0 disables backups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup_generations: Option<u32>,
+    //Generation of the credential DB last saved (see integrity.rs). A DB 
+    //with an older generation is a rolled back copy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    //Base64 encoded HMAC-SHA256 of the generation, keyed from the vault key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation_mac: Option<String>,
+    //Base64 encoded master key itself, as stored by older versions.
+    //Replaced by `verifier` on the next successful unlock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub master_key_hash: Option<String>,
+}
+
+//Credential DB file when the vault is sealed: the serialized CredentialStore
+//is encrypted as a whole with the vault key. Only this small header is 
+//stored in plaintext, and it is authenticated along with the payload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealedVault {
+    //Always "pwmgr-sealed-vault"
+    pub format: String,
+    pub version: u32,
+    //KDF parameters of the master key that wrapped the vault key, as 
+    //written before key slots existed (omitted since)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    //The serialized CredentialStore, encrypted with the vault key as a 
+    //$pwmgr$ envelope (see lib::encrypt_bytes)
+    pub payload: String,
+    //Integrity MAC of the credentials in the payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<VaultIntegrity>,
+}
+
+//Credential DB file when the vault is not sealed: the credentials, with 
+//their integrity MAC. Older files hold just the CredentialStore, and are 
+//migrated to this layout when read (see migrate.rs).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VaultDocument {
+    //Always "pwmgr-vault"
+    pub format: String,
+    //Layout version, see VAULT_DOCUMENT_VERSION
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<VaultIntegrity>,
+    pub credentials: CredentialStore,
+}
+
+//Keyed MAC over all credentials and the generation counter, which is 
+//incremented every time the credentials are saved (see integrity.rs)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VaultIntegrity {
+    pub generation: u64,
+    //Base64 encoded HMAC-SHA256
+    pub mac: String,
+}
+
+//Failed unlock attempts, kept in the master key file (see throttle.rs)
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ThrottleState {
+    //Consecutive failed unlock attempts
+    pub failures: u32,
+    //Time of the last failed attempt, in seconds since the UNIX epoch
+    pub last_failure: u64,
+}
+
+//What a journal entry did to a credential
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalOp {
+    Add,
+    Update,
+    Delete,
+    //Reverted an earlier change
+    Undo,
+    //Applied an undone change again
+    Redo,
+}
+
+//A change to one credential, recorded in the journal (see journal.rs)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    //Position in the journal, starting at 1
+    pub seq: u64,
+    //Time of the change, in seconds since the UNIX epoch
+    pub timestamp: u64,
+    pub op: JournalOp,
+    pub site: String,
+    pub user: String,
+    //The credential before and after the change (None if it did not exist)
+    pub previous: Option<Credential>,
+    pub current: Option<Credential>,
+    //Undo and redo: seq of the change undone or redone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
+}
+
+//Saves in progress, persisted next to the master key file while the vault 
+//files are being replaced (see persist::Transaction)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Transaction {
+    pub renames: Vec<PendingRename>,
+}
+
+//A fully written temporary file, and the file it replaces
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingRename {
+    pub from: String,
+    pub to: String,
 }
@@ -0,0 +1,163 @@
+//Throttling of failed unlock attempts.
+//
+//Each failed attempt is recorded in the master key file. After a few free 
+//attempts, every further attempt has to wait twice as long as the previous 
+//one, and with a lockout threshold configured the vault refuses to unlock at
+//all once the threshold is reached (only the recovery shares lift it). A 
+//legacy master key file (a bare hash) has no room for the count, which is 
+//kept in "<master key file>.throttle" until the vault is migrated on its 
+//next unlock.
+//
+//Nothing secret is available before unlocking, so the count is not 
+//authenticated: whoever can write the master key file can reset it, by 
+//deleting its "throttle" entry. The throttle slows down guessing through 
+//pwmgr; an attacker holding the files can run the KDF offline anyway. An 
+//authenticated count would take a secret kept outside the vault files (an
+//OS keyring, a hardware token), which pwmgr does not have yet.
+
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::MasterKeyRecord;
+use crate::structs::ThrottleState;
+
+//Failed attempts allowed before any delay applies
+pub const FREE_FAILURES: u32 = 3;
+//Upper bound of the delay between two attempts
+pub const MAX_DELAY_SECS: u64 = 3600;
+
+//Whether an unlock attempt may go ahead
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnlockPermission {
+    Allowed,
+    //Too many recent failures, retry after this long
+    RetryAfter(Duration),
+    //The lockout threshold has been reached
+    LockedOut,
+}
+
+pub struct Throttle {
+    //File the state of a legacy master key file is kept in
+    legacy_file_name: String,
+    pub state: ThrottleState,
+    lockout_threshold: Option<u32>,
+}
+
+/// Returns the current time in seconds since the UNIX epoch
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Splits seconds since the UNIX epoch into the UTC year, month, day, hour,
+/// minute and second
+pub fn utc_date_time(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    //Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, 
+     secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60)
+}
+
+/// Formats seconds since the UNIX epoch as "YYYY-MM-DD HH:MM:SS UTC"
+pub fn format_utc_time(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = utc_date_time(secs);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", 
+            year, month, day, hour, minute, second)
+}
+
+/// Delay required after `failures` consecutive failed attempts
+pub fn backoff_delay(failures: u32) -> Duration {
+    if failures < FREE_FAILURES {
+        return Duration::ZERO;
+    }
+    let exponent = (failures - FREE_FAILURES).min(63);
+    Duration::from_secs(1u64.checked_shl(exponent)
+                        .unwrap_or(MAX_DELAY_SECS)
+                        .min(MAX_DELAY_SECS))
+}
+
+impl Throttle {
+    /// Loads the throttle state of the vault whose master key file is 
+    /// `master_key_file_name`, as read into `record`
+    pub fn load(master_key_file_name: &str, record: &MasterKeyRecord) 
+           -> Throttle {
+        let legacy_file_name = format!("{}.throttle", master_key_file_name);
+        let (state, lockout_threshold) = match record {
+            MasterKeyRecord::Current(key_file) => 
+                (key_file.throttle.clone().unwrap_or_default(), 
+                 key_file.lockout_threshold),
+            //A legacy throttle file that cannot be read counts as no 
+            //failures, as a deleted one would
+            MasterKeyRecord::Legacy(_) => 
+                (fs::read_to_string(&legacy_file_name).ok()
+                 .and_then(|contents| serde_json::from_str(&contents).ok())
+                 .unwrap_or_default(), None),
+        };
+        Throttle {legacy_file_name, state, lockout_threshold}
+    }
+
+    /// Tells whether an unlock attempt may go ahead at time `now`
+    pub fn check(&self, now: u64) -> UnlockPermission {
+        if let Some(threshold) = self.lockout_threshold {
+            if threshold > 0 && self.state.failures >= threshold {
+                return UnlockPermission::LockedOut;
+            }
+        }
+        let allowed_at = self.state.last_failure
+            .saturating_add(backoff_delay(self.state.failures).as_secs());
+        if now < allowed_at {
+            UnlockPermission::RetryAfter(Duration::from_secs(allowed_at - now))
+        }
+        else {
+            UnlockPermission::Allowed
+        }
+    }
+
+    /// Records a failed unlock attempt at time `now` in `record`, which the 
+    /// caller writes (or next to a legacy master key file)
+    pub fn record_failure(&mut self, now: u64, record: &mut MasterKeyRecord) 
+           -> Result<(), Box<dyn Error>> {
+        self.state.failures = self.state.failures.saturating_add(1);
+        self.state.last_failure = now;
+        self.store(record)
+    }
+
+    /// Clears the failed attempts after a successful unlock, in `record`, 
+    /// which the caller writes (or next to a legacy master key file)
+    pub fn clear(&mut self, record: &mut MasterKeyRecord) 
+           -> Result<(), Box<dyn Error>> {
+        self.state = ThrottleState::default();
+        self.store(record)
+    }
+
+    fn store(&self, record: &mut MasterKeyRecord) 
+       -> Result<(), Box<dyn Error>> {
+        let state = (self.state.failures > 0).then(|| self.state.clone());
+        match (record, state) {
+            (MasterKeyRecord::Current(key_file), state) => 
+                key_file.throttle = state,
+            (MasterKeyRecord::Legacy(_), Some(state)) => fs::write(
+                &self.legacy_file_name, serde_json::to_string_pretty(&state)?)?,
+            (MasterKeyRecord::Legacy(_), None) => 
+                match fs::remove_file(&self.legacy_file_name) {
+                    Err(error) if error.kind() != ErrorKind::NotFound => 
+                        return Err(error.into()),
+                    _ => {},
+                },
+        }
+        Ok(())
+    }
+}